@@ -0,0 +1,184 @@
+use crate::scheme::Scheme;
+use crate::{node_count, Leaf, MerkleTree};
+use digest::Digest;
+use std::vec::Vec;
+
+/// A set of changes to a `MerkleTree` that are hashed together when the batch
+/// is committed or dropped, so that each changed node is hashed only once.
+pub struct Batch<'a, D: Digest, T, S: Scheme<D>, const K: usize = 2> {
+    tree: &'a mut MerkleTree<D, T, S, K>,
+    // The number of leaves before the batch.
+    len: usize,
+    // Leaves that were replaced in place.
+    replaced: Vec<usize>,
+    // The first leaf that may have moved. Every leaf from here on is dirty.
+    shifted: Option<usize>,
+}
+
+impl<D: Digest, T, S: Scheme<D>, const K: usize> MerkleTree<D, T, S, K> {
+    pub fn batch(&mut self) -> Batch<'_, D, T, S, K> {
+        Batch {
+            len: self.leaves.len(),
+            tree: self,
+            replaced: Vec::new(),
+            shifted: None,
+        }
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>, const K: usize> Batch<'_, D, T, S, K> {
+    pub fn insert(&mut self, i: usize, leaf: Leaf<D, T>) {
+        self.tree.leaves.insert(i, leaf);
+        self.shift(i);
+    }
+
+    pub fn remove(&mut self, i: usize) -> Leaf<D, T> {
+        let leaf = self.tree.leaves.remove(i);
+        self.shift(i);
+        leaf
+    }
+
+    pub fn swap_remove(&mut self, i: usize) -> Leaf<D, T> {
+        let leaf = self.tree.leaves.swap_remove(i);
+        self.replaced.push(i);
+        self.shift(self.tree.leaves.len());
+        leaf
+    }
+
+    pub fn replace(&mut self, i: usize, leaf: Leaf<D, T>) -> Leaf<D, T> {
+        self.replaced.push(i);
+        std::mem::replace(&mut self.tree.leaves[i], leaf)
+    }
+
+    pub fn push(&mut self, leaf: Leaf<D, T>) {
+        self.shift(self.tree.leaves.len());
+        self.tree.leaves.push(leaf);
+    }
+
+    /// Rehash the tree. This is the same as dropping the batch.
+    pub fn commit(self) {}
+
+    fn shift(&mut self, i: usize) {
+        self.shifted = Some(self.shifted.map_or(i, |j| j.min(i)));
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>, const K: usize> Drop for Batch<'_, D, T, S, K> {
+    fn drop(&mut self) {
+        let tree = &mut *self.tree;
+        let l = node_count::<K>(tree.leaves.len()).expect("too many leaves");
+        if l != tree.nodes.len() {
+            tree.nodes.resize(l, None);
+            tree.rehash_all();
+            return;
+        }
+        if l == 0 {
+            return;
+        }
+        let shifted = self.shifted.unwrap_or(usize::MAX);
+        let mut dirty: Vec<usize> = self
+            .replaced
+            .iter()
+            .filter(|&&i| i < shifted)
+            .map(|&i| tree.leaf_parent(i))
+            .collect();
+        if shifted < usize::MAX {
+            // Stop at the last leaf that was real before the batch or is now.
+            let last = self.len.max(tree.leaves.len()) - 1;
+            dirty.extend(tree.leaf_parent(shifted)..=tree.leaf_parent(last));
+        }
+        tree.rehash_set(dirty);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::leaf;
+    use crate::{MerkleTree, Rfc6962};
+
+    fn matches_eager_updates<const K: usize>() {
+        for n in 0..12 {
+            let mut eager: MerkleTree<_, _, Rfc6962, K> = (0..n).map(leaf).collect();
+            let mut batched: MerkleTree<_, _, Rfc6962, K> = (0..n).map(leaf).collect();
+            let mut batch = batched.batch();
+            for k in 0..n {
+                let i = (k * 7) % (n + k / 2);
+                match k % 4 {
+                    0 => {
+                        eager.push(leaf(100 + k));
+                        batch.push(leaf(100 + k));
+                    }
+                    1 => {
+                        eager.replace(i, leaf(200 + k));
+                        batch.replace(i, leaf(200 + k));
+                    }
+                    2 => {
+                        eager.insert(i, leaf(300 + k));
+                        batch.insert(i, leaf(300 + k));
+                    }
+                    _ => {
+                        assert_eq!(eager.remove(i).data, batch.remove(i).data);
+                    }
+                }
+            }
+            batch.commit();
+            assert_eq!(batched.nodes, eager.nodes, "K={} n={}", K, n);
+            assert_eq!(batched.hash(), eager.hash());
+        }
+    }
+
+    #[test]
+    fn batch_matches_eager_updates() {
+        matches_eager_updates::<2>();
+    }
+
+    #[test]
+    fn wide_batch_matches_eager_updates() {
+        matches_eager_updates::<3>();
+    }
+
+    #[test]
+    fn replacements_only() {
+        let mut eager: MerkleTree<_, _, Rfc6962> = (0..13).map(leaf).collect();
+        let mut batched: MerkleTree<_, _, Rfc6962> = (0..13).map(leaf).collect();
+        {
+            let mut batch = batched.batch();
+            for i in [12, 0, 5, 4, 5] {
+                assert_eq!(
+                    eager.replace(i, leaf(50 + i)).data,
+                    batch.replace(i, leaf(50 + i)).data
+                );
+            }
+        }
+        assert_eq!(batched.nodes, eager.nodes);
+    }
+
+    // Leaves removed from the end leave padding behind, which must be rehashed
+    // even though the batch ends with fewer leaves than it started with.
+    #[test]
+    fn removals_from_the_end() {
+        let mut eager: MerkleTree<_, _, Rfc6962> = (0..13).map(leaf).collect();
+        let mut batched: MerkleTree<_, _, Rfc6962> = (0..13).map(leaf).collect();
+        let mut batch = batched.batch();
+        for i in [12, 11, 9] {
+            assert_eq!(eager.remove(i).data, batch.remove(i).data);
+        }
+        batch.commit();
+        assert_eq!(batched.nodes, eager.nodes);
+    }
+
+    #[test]
+    fn swap_removals() {
+        for n in 1..12 {
+            let mut eager: MerkleTree<_, _, Rfc6962> = (0..n).map(leaf).collect();
+            let mut batched: MerkleTree<_, _, Rfc6962> = (0..n).map(leaf).collect();
+            let mut batch = batched.batch();
+            for k in 0..n / 2 + 1 {
+                let i = (k * 5) % (n - k);
+                assert_eq!(eager.swap_remove(i).data, batch.swap_remove(i).data);
+            }
+            batch.commit();
+            assert_eq!(batched.nodes, eager.nodes, "n={}", n);
+        }
+    }
+}
@@ -0,0 +1,234 @@
+use crate::scheme::Scheme;
+use crate::{height, MerkleTree, SyncError};
+use digest::{Digest, Output};
+use std::convert::Infallible;
+use std::vec::Vec;
+
+/// Read access to the node hashes of a tree that may live elsewhere, such as
+/// on a replica that answers queries over the network.
+///
+/// Nodes are addressed by level and position within the level. Level 0 holds
+/// the leaf hashes, level 1 the nodes directly above them, and so on up to the
+/// root.
+pub trait NodeSource<D: Digest> {
+    type Error;
+
+    /// The number of leaves in the tree.
+    fn size(&self) -> usize;
+
+    /// The hash of node `p` of level `h`. This is only asked for nodes that
+    /// cover at least one leaf.
+    fn node(&self, h: usize, p: usize) -> Result<Output<D>, Self::Error>;
+}
+
+impl<D: Digest, T, S: Scheme<D>> NodeSource<D> for MerkleTree<D, T, S> {
+    type Error = Infallible;
+
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    fn node(&self, h: usize, p: usize) -> Result<Output<D>, Infallible> {
+        Ok(self.node_ref(h, p).clone())
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>> MerkleTree<D, T, S> {
+    /// The indices of the leaves that differ between this tree and `other`,
+    /// in ascending order. Leaves that only one of the trees has count as
+    /// different.
+    pub fn diff<U>(&self, other: &MerkleTree<D, U, S>) -> Vec<usize> {
+        match self.diff_remote(other) {
+            Ok(diff) => diff,
+            Err(SyncError::Transport(e)) => match e {},
+            // A tree that exists is never too large to have a height.
+            Err(SyncError::BadResponse) => unreachable!(),
+        }
+    }
+
+    /// Like `diff`, but against a tree that is only reachable through
+    /// `NodeSource`. Only nodes whose hashes differ are descended into, so the
+    /// number of queries grows with the number of differing leaves rather than
+    /// the size of the tree, unless `S` is not `Scheme::ORDERED`. Fails with
+    /// `SyncError::BadResponse` if `other` claims more leaves than any tree
+    /// can have.
+    pub fn diff_remote<R: NodeSource<D>>(
+        &self,
+        other: &R,
+    ) -> Result<Vec<usize>, SyncError<R::Error>> {
+        let mut descent =
+            Descent::new::<D, S>(self.leaves.len(), other.size()).ok_or(SyncError::BadResponse)?;
+        while !descent.pending.is_empty() {
+            let hashes = descent
+                .pending
+                .iter()
+                .map(|&(h, p)| other.node(h, p))
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(SyncError::Transport)?;
+            descent.step(self, &hashes);
+        }
+        Ok(descent.finish())
+    }
+}
+
+// A comparison of a local tree of `n` leaves with another of `m`, one level at
+// a time from the highest level both trees have, used by both `diff_remote`
+// and `SyncClient`. Only nodes that cover leaves both trees have are visited.
+pub(crate) struct Descent {
+    pub(crate) n: usize,
+    pub(crate) m: usize,
+    // Whether equal hashes mean equal subtrees, so need not be descended into.
+    prune: bool,
+    // The nodes of the other tree whose hashes are needed next.
+    pub(crate) pending: Vec<(usize, usize)>,
+    diff: Vec<usize>,
+}
+
+impl Descent {
+    // Start comparing, or return `None` if either size is too large for a tree.
+    pub(crate) fn new<D: Digest, S: Scheme<D>>(n: usize, m: usize) -> Option<Self> {
+        let top = height::<2>(n)?.min(height::<2>(m)?);
+        let common = n.min(m);
+        Some(Descent {
+            n,
+            m,
+            prune: S::ORDERED,
+            pending: (0..common.div_ceil(1 << top)).map(|p| (top, p)).collect(),
+            diff: Vec::new(),
+        })
+    }
+
+    // Compare the other tree's `hashes` for the pending nodes with those of
+    // `tree`, and move down a level.
+    pub(crate) fn step<D: Digest, T, S: Scheme<D>>(
+        &mut self,
+        tree: &MerkleTree<D, T, S>,
+        hashes: &[Output<D>],
+    ) {
+        let common = self.n.min(self.m);
+        let mut next = Vec::new();
+        for (&(h, p), hash) in self.pending.iter().zip(hashes) {
+            let equal = tree.node_ref(h, p) == hash;
+            if h == 0 {
+                if !equal {
+                    self.diff.push(p);
+                }
+                continue;
+            }
+            if equal && self.prune {
+                continue;
+            }
+            for c in [2 * p, 2 * p + 1] {
+                if c << (h - 1) < common {
+                    next.push((h - 1, c));
+                }
+            }
+        }
+        self.pending = next;
+    }
+
+    // The differing leaves, including those that only one tree has.
+    pub(crate) fn finish(self) -> Vec<usize> {
+        let mut diff = self.diff;
+        diff.extend(self.n.min(self.m)..self.n.max(self.m));
+        diff
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NodeSource;
+    use crate::testing::{leaf, tree};
+    use crate::{MerkleTree, Output, Rfc6962, Sorted, SyncError};
+    use sha2::Sha256;
+    use std::cell::Cell;
+    use std::convert::Infallible;
+
+    #[test]
+    fn finds_changed_leaves() {
+        let a = tree::<Rfc6962>(100);
+        let mut b = tree::<Rfc6962>(100);
+        assert!(a.diff(&b).is_empty());
+        for &i in &[99, 3, 64, 4] {
+            b.replace(i, leaf(1000 + i));
+        }
+        assert_eq!(a.diff(&b), vec![3, 4, 64, 99]);
+        assert_eq!(b.diff(&a), vec![3, 4, 64, 99]);
+    }
+
+    #[test]
+    fn different_sizes() {
+        for (n, m) in [(0, 5), (1, 2), (3, 9), (16, 17), (30, 70)] {
+            let (a, mut b) = (tree::<Rfc6962>(n), tree::<Rfc6962>(m));
+            assert_eq!(a.diff(&b), (n..m).collect::<Vec<_>>(), "n={} m={}", n, m);
+            if n > 0 {
+                b.replace(n - 1, leaf(1000));
+                assert_eq!(a.diff(&b), (n - 1..m).collect::<Vec<_>>());
+            }
+        }
+    }
+
+    struct Counting<'a> {
+        tree: &'a MerkleTree<Sha256, usize, Rfc6962>,
+        queries: Cell<usize>,
+    }
+
+    impl NodeSource<Sha256> for Counting<'_> {
+        type Error = Infallible;
+
+        fn size(&self) -> usize {
+            self.tree.size()
+        }
+
+        fn node(&self, h: usize, p: usize) -> Result<Output<Sha256>, Infallible> {
+            self.queries.set(self.queries.get() + 1);
+            self.tree.node(h, p)
+        }
+    }
+
+    #[test]
+    fn queries_only_differing_paths() {
+        let a = tree::<Rfc6962>(1024);
+        let mut b = tree::<Rfc6962>(1024);
+        b.replace(700, leaf(0));
+        let remote = Counting {
+            tree: &b,
+            queries: Cell::new(0),
+        };
+        assert_eq!(a.diff_remote(&remote), Ok(vec![700]));
+        // The root, then both children of each node on the path down.
+        assert_eq!(remote.queries.get(), 1 + 2 * 10);
+    }
+
+    // Sorted hashing gives [0, 1] and [1, 0] the same node, so equal hashes
+    // must not stop the descent.
+    #[test]
+    fn sorted_trees_compare_every_leaf() {
+        let a: MerkleTree<_, _, Sorted> = vec![0, 1, 2, 3].into_iter().map(leaf).collect();
+        let b: MerkleTree<_, _, Sorted> = vec![1, 0, 2, 3].into_iter().map(leaf).collect();
+        assert_eq!(a.hash(), b.hash());
+        assert_eq!(a.diff(&b), vec![0, 1]);
+        assert!(a.diff(&a).is_empty());
+    }
+
+    struct Oversized;
+
+    impl NodeSource<Sha256> for Oversized {
+        type Error = Infallible;
+
+        fn size(&self) -> usize {
+            usize::MAX
+        }
+
+        fn node(&self, _: usize, _: usize) -> Result<Output<Sha256>, Infallible> {
+            unreachable!()
+        }
+    }
+
+    // A size this large used to overflow computing the tree's height.
+    #[test]
+    fn rejects_oversized_sources() {
+        let tree = tree::<Rfc6962>(4);
+        assert_eq!(tree.diff_remote(&Oversized), Err(SyncError::BadResponse));
+    }
+}
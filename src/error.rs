@@ -0,0 +1,23 @@
+use std::fmt;
+
+/// Why a mutation could not be applied to a tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IndexError {
+    /// The tree has no leaves to remove or replace.
+    Empty,
+    /// The index is past the end of the tree's `len` leaves.
+    OutOfRange { index: usize, len: usize },
+}
+
+impl fmt::Display for IndexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IndexError::Empty => write!(f, "tree is empty"),
+            IndexError::OutOfRange { index, len } => {
+                write!(f, "index {} is out of range for {} leaves", index, len)
+            }
+        }
+    }
+}
+
+impl std::error::Error for IndexError {}
@@ -0,0 +1,112 @@
+use crate::scheme::Scheme;
+use crate::MerkleTree;
+use digest::{Digest, Output};
+use std::ops::{Deref, DerefMut};
+
+/// Mutable access to the data of a leaf, made with `MerkleTree::leaf_mut`.
+/// When dropped, the leaf is rehashed and, if its hash changed, so are the
+/// nodes above it.
+pub struct LeafMut<'a, D, T, S, F, const K: usize = 2>
+where
+    D: Digest,
+    S: Scheme<D>,
+    F: FnOnce(&T) -> Output<D>,
+{
+    tree: &'a mut MerkleTree<D, T, S, K>,
+    i: usize,
+    hasher: Option<F>,
+}
+
+impl<D: Digest, T, S: Scheme<D>, const K: usize> MerkleTree<D, T, S, K> {
+    /// Mutable access to the data of leaf `i`, or `None` if there is no such
+    /// leaf. When the guard is dropped, `hasher` computes the leaf's new hash
+    /// from its data, and only the path above it is rehashed.
+    pub fn leaf_mut<F: FnOnce(&T) -> Output<D>>(
+        &mut self,
+        i: usize,
+        hasher: F,
+    ) -> Option<LeafMut<'_, D, T, S, F, K>> {
+        if i >= self.leaves.len() {
+            return None;
+        }
+        Some(LeafMut {
+            tree: self,
+            i,
+            hasher: Some(hasher),
+        })
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>, F: FnOnce(&T) -> Output<D>, const K: usize> Deref
+    for LeafMut<'_, D, T, S, F, K>
+{
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.tree.leaves[self.i].data
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>, F: FnOnce(&T) -> Output<D>, const K: usize> DerefMut
+    for LeafMut<'_, D, T, S, F, K>
+{
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.tree.leaves[self.i].data
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>, F: FnOnce(&T) -> Output<D>, const K: usize> Drop
+    for LeafMut<'_, D, T, S, F, K>
+{
+    fn drop(&mut self) {
+        let hasher = self.hasher.take().unwrap();
+        let leaf = &mut self.tree.leaves[self.i];
+        let hash = hasher(&leaf.data);
+        if hash != leaf.hash {
+            leaf.hash = hash;
+            let j = self.tree.leaf_parent(self.i);
+            self.tree.rehash_nodes(j, j + 1);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{leaf, tree};
+    use crate::{MerkleTree, Output, Rfc6962};
+    use sha2::Sha256;
+
+    fn hash(i: &usize) -> Output<Sha256> {
+        leaf(*i).hash
+    }
+
+    #[test]
+    fn guard_rehashes_on_drop() {
+        let mut tree = tree::<Rfc6962>(3);
+        *tree.leaf_mut(1, hash).unwrap() += 10;
+        let expected: MerkleTree<_, _, Rfc6962> = vec![0, 11, 2].into_iter().map(leaf).collect();
+        assert_eq!(tree.hash(), expected.hash());
+        assert_eq!(tree.leaf(1).unwrap().hash, expected.leaf(1).unwrap().hash);
+        assert!(tree.leaf_mut(3, |_| unreachable!()).is_none());
+    }
+
+    #[test]
+    fn data_mut_leaves_hashes_alone() {
+        let mut tree = tree::<Rfc6962>(3);
+        let root = tree.hash();
+        *tree.data_mut(2).unwrap() = 7;
+        assert_eq!(tree.leaf(2).unwrap().data, 7);
+        assert_eq!(tree.leaf(2).unwrap().hash, leaf(2).hash);
+        assert_eq!(tree.hash(), root);
+        assert!(tree.data_mut(3).is_none());
+    }
+
+    #[test]
+    fn wide_guard_rehashes_on_drop() {
+        let mut tree: MerkleTree<_, _, Rfc6962, 4> = (0..5).map(leaf).collect();
+        *tree.leaf_mut(4, hash).unwrap() = 9;
+        let expected: MerkleTree<_, _, Rfc6962, 4> =
+            vec![0, 1, 2, 3, 9].into_iter().map(leaf).collect();
+        assert_eq!(tree.nodes, expected.nodes);
+    }
+}
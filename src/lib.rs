@@ -1,6 +1,47 @@
 pub use digest::{Digest, Output};
+use scheme::combine;
+use std::iter::FromIterator;
+use std::marker::PhantomData;
 use std::vec::Vec;
 
+mod batch;
+mod diff;
+mod error;
+mod guard;
+mod log;
+mod map;
+mod mmr;
+mod persistent;
+mod proof;
+mod scheme;
+#[cfg(feature = "serde")]
+mod serialize;
+mod snapshot;
+mod sorted;
+mod sparse;
+mod sync;
+#[cfg(test)]
+mod testing;
+mod wire;
+
+pub use batch::Batch;
+pub use diff::NodeSource;
+pub use error::IndexError;
+pub use guard::LeafMut;
+pub use log::{ConsistencyProof, Log};
+pub use map::{MapProof, MapProofNode, MerkleMap};
+pub use mmr::Mmr;
+pub use persistent::PersistentTree;
+pub use proof::{GapProof, MultiProof, Proof};
+pub use scheme::{Bitcoin, Legacy, Rfc6962, Scheme, Sorted};
+pub use snapshot::Snapshot;
+pub use sorted::SortedTree;
+pub use sparse::{SparseMerkleTree, SparseProof};
+pub use sync::{
+    channel, Channel, ChannelServer, Request, Response, SyncClient, SyncError, Transport,
+};
+pub use wire::DecodeError;
+
 pub struct Leaf<D: Digest, T> {
     pub hash: Output<D>,
     pub data: T,
@@ -12,116 +53,460 @@ impl<D: Digest, T> Leaf<D, T> {
     }
 }
 
-pub struct MerkleTree<D: Digest, T> {
-    nodes: Vec<Output<D>>,
+impl<D: Digest, T: LeafHash<D>> Leaf<D, T> {
+    pub fn from_data(data: T) -> Self {
+        Self::new(data.leaf_hash(), data)
+    }
+}
+
+/// Data that knows how to hash itself into a leaf. Anything that is a byte
+/// slice is hashed as those bytes.
+pub trait LeafHash<D: Digest> {
+    fn leaf_hash(&self) -> Output<D>;
+}
+
+impl<D: Digest, T: AsRef<[u8]> + ?Sized> LeafHash<D> for T {
+    fn leaf_hash(&self) -> Output<D> {
+        D::digest(self.as_ref())
+    }
+}
+
+/// A Merkle tree over a list of leaves. Each node has `K` children, two by
+/// default; wider trees give shorter proofs.
+///
+/// Multiproofs, gap proofs, diffs, sync, and the serde and byte encodings are
+/// only available for binary trees.
+pub struct MerkleTree<D: Digest, T, S: Scheme<D> = Legacy, const K: usize = 2> {
+    nodes: Vec<Option<Output<D>>>,
     leaves: Vec<Leaf<D, T>>,
+    scheme: PhantomData<S>,
 }
 
-impl<D: Digest, T> MerkleTree<D, T> {
+impl<D: Digest, T, S: Scheme<D>, const K: usize> MerkleTree<D, T, S, K> {
     pub fn new() -> Self {
+        assert!(K >= 2, "nodes need at least two children");
         Self {
             nodes: Vec::new(),
             leaves: Vec::new(),
+            scheme: PhantomData,
         }
     }
 
+    /// Build a tree over `leaves` in one pass, hashing each node once.
+    pub fn from_leaves(leaves: Vec<Leaf<D, T>>) -> Self {
+        assert!(K >= 2, "nodes need at least two children");
+        let l = node_count::<K>(leaves.len()).expect("too many leaves");
+        let mut tree = Self {
+            nodes: vec![None; l],
+            leaves,
+            scheme: PhantomData,
+        };
+        tree.rehash_all();
+        tree
+    }
+
     pub fn hash(&self) -> Output<D> {
         if self.nodes.is_empty() {
-            Output::<D>::default()
+            // A single leaf is its own root.
+            match self.leaves.first() {
+                Some(leaf) => S::leaf(&leaf.hash),
+                None => S::empty(),
+            }
         } else {
-            self.nodes[0].clone()
+            self.nodes[0].clone().unwrap_or_else(S::empty)
         }
     }
 
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn leaf(&self, i: usize) -> Option<&Leaf<D, T>> {
+        self.leaves.get(i)
+    }
+
+    /// Mutable access to the data of leaf `i`. Its hash is left as it is; use
+    /// `leaf_mut` to have it recomputed.
+    pub fn data_mut(&mut self, i: usize) -> Option<&mut T> {
+        self.leaves.get_mut(i).map(|l| &mut l.data)
+    }
+
+    pub fn leaves(&self) -> std::slice::Iter<'_, Leaf<D, T>> {
+        self.leaves.iter()
+    }
+
+    /// The number of levels of nodes above the leaves, which are at level 0.
+    /// The root is at this level, except in a tree of one leaf, whose root is
+    /// that leaf's hash as the scheme hashes leaves.
+    pub fn depth(&self) -> usize {
+        height::<K>(self.leaves.len()).expect("too many leaves")
+    }
+
+    /// The hashes at level `k`, left to right. Level 0 holds the leaf hashes
+    /// and, in a tree of more than one leaf, level `depth()` holds the root.
+    /// Nodes past the last leaf are left out.
+    pub fn level(&self, k: usize) -> impl Iterator<Item = &Output<D>> {
+        let n = if k <= self.depth() {
+            self.leaves.len().div_ceil(K.pow(k as u32))
+        } else {
+            0
+        };
+        (0..n).map(move |p| self.node_ref(k, p))
+    }
+
+    /// The hash at position `i` of `level`, numbered as in `level`.
+    pub fn node_hash(&self, level: usize, i: usize) -> Option<&Output<D>> {
+        if level > self.depth() || i.checked_mul(K.pow(level as u32))? >= self.leaves.len() {
+            return None;
+        }
+        Some(self.node_ref(level, i))
+    }
+
     pub fn insert(&mut self, i: usize, leaf: Leaf<D, T>) {
         self.leaves.insert(i, leaf);
-        if self.leaves.len() == self.nodes.len() + 2 {
+        let l = node_count::<K>(self.leaves.len()).expect("too many leaves");
+        if l != self.nodes.len() {
             // we need to create a new level of nodes. insert new 'parents' for
             // leaves. We then have to rehash all nodes.
-            let l = self.nodes.len() + (self.leaves.len() * 2);
-            self.nodes.resize(l, Output::<D>::default());
-            self.rehash_nodes(0, l);
+            self.nodes.resize(l, None);
+            self.rehash_all();
         } else {
-            self.rehash_nodes(i, self.nodes.len());
+            self.rehash_leaves(i, self.leaves.len());
+        }
+    }
+
+    /// Remove and return leaf `i`, shifting the leaves after it down.
+    pub fn remove(&mut self, i: usize) -> Leaf<D, T> {
+        let leaf = self.leaves.remove(i);
+        if !self.shrink() {
+            // The old last leaf is padding now, so its path changes too.
+            self.rehash_leaves(i, self.leaves.len() + 1);
+        }
+        leaf
+    }
+
+    /// Remove and return leaf `i`, moving the last leaf into its place. Only
+    /// the two affected paths are rehashed.
+    pub fn swap_remove(&mut self, i: usize) -> Leaf<D, T> {
+        let leaf = self.leaves.swap_remove(i);
+        if !self.shrink() {
+            let n = self.leaves.len();
+            self.rehash_set(vec![self.leaf_parent(i), self.leaf_parent(n)]);
+        }
+        leaf
+    }
+
+    /// Replace leaf `i`, returning the old one.
+    pub fn replace(&mut self, i: usize, leaf: Leaf<D, T>) -> Leaf<D, T> {
+        let old = std::mem::replace(&mut self.leaves[i], leaf);
+        let j = self.leaf_parent(i);
+        self.rehash_nodes(j, j + 1);
+        old
+    }
+
+    /// Like `insert`, but returns an error instead of panicking if `i` is
+    /// greater than `len()`.
+    pub fn try_insert(&mut self, i: usize, leaf: Leaf<D, T>) -> Result<(), IndexError> {
+        self.check_index(i, self.leaves.len() + 1)?;
+        self.insert(i, leaf);
+        Ok(())
+    }
+
+    /// Like `remove`, but returns an error instead of panicking if there is no
+    /// leaf `i`.
+    pub fn try_remove(&mut self, i: usize) -> Result<Leaf<D, T>, IndexError> {
+        self.check_index(i, self.leaves.len())?;
+        Ok(self.remove(i))
+    }
+
+    /// Like `replace`, but returns an error instead of panicking if there is no
+    /// leaf `i`.
+    pub fn try_replace(&mut self, i: usize, leaf: Leaf<D, T>) -> Result<Leaf<D, T>, IndexError> {
+        self.check_index(i, self.leaves.len())?;
+        Ok(self.replace(i, leaf))
+    }
+
+    pub fn push(&mut self, leaf: Leaf<D, T>) {
+        self.insert(self.leaves.len(), leaf);
+    }
+
+    pub fn insert_data(&mut self, i: usize, data: T)
+    where
+        T: LeafHash<D>,
+    {
+        self.insert(i, Leaf::from_data(data));
+    }
+
+    pub fn push_data(&mut self, data: T)
+    where
+        T: LeafHash<D>,
+    {
+        self.push(Leaf::from_data(data));
+    }
+
+    pub fn proof(&self, i: usize) -> Proof<D, S, K> {
+        assert!(i < self.leaves.len(), "leaf index out of range");
+        // Walk up from the leaf, collecting the siblings of each node. Siblings
+        // past the last leaf are padding, which the verifier recomputes.
+        let mut siblings = Vec::new();
+        let (mut h, mut j, mut width) = (0, i, 1);
+        while width < self.leaves.len() {
+            let first = j - j % K;
+            for m in first..first + K {
+                if m != j && m * width < self.leaves.len() {
+                    siblings.push(self.node_ref(h, m).clone());
+                }
+            }
+            h += 1;
+            j /= K;
+            width *= K;
         }
+        Proof::new(siblings)
     }
 
-    pub fn remove(&mut self, i: usize) {
-        self.leaves.remove(i);
+    // Drop a level of nodes if a removal left the tree with few enough leaves,
+    // rehashing everything. Returns whether it did.
+    fn shrink(&mut self) -> bool {
+        let l = node_count::<K>(self.leaves.len()).expect("too many leaves");
         if self.leaves.is_empty() {
             self.nodes.clear();
-        } else if self.leaves.len() == (self.nodes.len() + 1) / 2 {
-            let l = self.nodes.len() - self.leaves.len();
+        } else if l != self.nodes.len() {
             self.nodes.truncate(l);
-            self.rehash_nodes(0, l);
+            self.rehash_all();
         } else {
-            self.rehash_nodes(self.leaf_parent(i), self.nodes.len());
+            return false;
         }
+        true
     }
 
-    pub fn replace(&mut self, i: usize, leaf: Leaf<D, T>) {
-        self.leaves[i] = leaf;
-        let j = self.leaf_parent(i);
-        self.rehash_nodes(j, j);
+    // Check that `i` is below `bound`, one past the last index the caller
+    // accepts.
+    fn check_index(&self, i: usize, bound: usize) -> Result<(), IndexError> {
+        if bound == 0 {
+            Err(IndexError::Empty)
+        } else if i >= bound {
+            Err(IndexError::OutOfRange {
+                index: i,
+                len: self.leaves.len(),
+            })
+        } else {
+            Ok(())
+        }
     }
 
-    pub fn push(&mut self, leaf: Leaf<D, T>) {
-        self.insert(self.leaves.len(), leaf);
+    #[cfg(not(feature = "rayon"))]
+    fn rehash_all(&mut self) {
+        self.rehash_nodes(0, self.nodes.len());
+    }
+
+    // Rehash every node, one level at a time from the bottom, hashing each
+    // level in parallel.
+    #[cfg(feature = "rayon")]
+    fn rehash_all(&mut self) {
+        use rayon::prelude::*;
+        let n = self.nodes.len();
+        if n == 0 {
+            return;
+        }
+        let hashes: Vec<&Output<D>> = self.leaves.iter().map(|l| &l.hash).collect();
+        let (mut upper, bottom) = self.nodes.split_at_mut(n / K);
+        bottom.par_iter_mut().enumerate().for_each(|(k, node)| {
+            let leaves: [Option<Output<D>>; K] =
+                std::array::from_fn(|c| hashes.get(K * k + c).map(|h| S::leaf(h)));
+            *node = combine::<D, S, K>(std::array::from_fn(|c| leaves[c].as_ref()));
+        });
+        let mut below = &*bottom;
+        while !upper.is_empty() {
+            let m = upper.len() / K;
+            let (rest, level) = std::mem::take(&mut upper).split_at_mut(m);
+            level.par_iter_mut().enumerate().for_each(|(k, node)| {
+                *node = combine::<D, S, K>(std::array::from_fn(|c| below[K * k + c].as_ref()));
+            });
+            below = level;
+            upper = rest;
+        }
+    }
+
+    // Rehash the nodes over leaves `start..end` and their ancestors. Nodes over
+    // nothing but padding keep the hash `rehash_all` gave them, so `end` need
+    // only reach past the last leaf that is real now or was before the change.
+    fn rehash_leaves(&mut self, start: usize, end: usize) {
+        if start < end {
+            self.rehash_nodes(self.leaf_parent(start), self.leaf_parent(end - 1) + 1);
+        }
     }
 
     fn rehash_nodes(&mut self, start: usize, end: usize) {
+        if self.nodes.is_empty() {
+            return;
+        }
         let (mut start, mut end) = (start, end);
         loop {
             for i in (start..end).rev() {
                 self.rehash_node(i);
             }
             if start == 0 {
-                return
+                return;
             }
             start = self.node_parent(start);
-            end = self.node_parent(end);    
+            end = self.node_parent(end - 1) + 1;
+        }
+    }
+
+    // Rehash the nodes in `dirty`, which must all be on the same level, and
+    // then each of their ancestors once.
+    fn rehash_set(&mut self, mut dirty: Vec<usize>) {
+        dirty.sort_unstable();
+        dirty.dedup();
+        while let Some(&first) = dirty.first() {
+            for &i in dirty.iter().rev() {
+                self.rehash_node(i);
+            }
+            if first == 0 {
+                return;
+            }
+            for i in dirty.iter_mut() {
+                *i = self.node_parent(*i);
+            }
+            dirty.dedup();
         }
     }
 
     fn rehash_node(&mut self, i: usize) {
-        let mut d = D::new();
-        if i * 2 + 1 >= self.nodes.len() {
+        self.nodes[i] = if i * K + 1 >= self.nodes.len() {
             // Our children are leaves.
             let j = self.left_child_leaf(i);
-            if j < self.leaves.len() {
-                d.update(self.leaves[j].hash.as_slice())
-            }
-            if j + 1 < self.leaves.len() {
-                d.update(self.leaves[j + 1].hash.as_slice())
-            }
+            let leaves: [Option<Output<D>>; K] =
+                std::array::from_fn(|c| self.leaves.get(j + c).map(|l| S::leaf(&l.hash)));
+            combine::<D, S, K>(std::array::from_fn(|c| leaves[c].as_ref()))
         } else {
             // Our children are nodes. If we are here, we should have a full
             // level of nodes below us.
             let j = self.left_child_node(i);
-            d.update(self.nodes[j].as_slice());
-            d.update(self.nodes[j + 1].as_slice());
+            combine::<D, S, K>(std::array::from_fn(|c| self.nodes[j + c].as_ref()))
+        };
+    }
+
+    // The hash at position `p` of level `h`, which must cover a leaf.
+    fn node_ref(&self, h: usize, p: usize) -> &Output<D> {
+        if h == 0 {
+            &self.leaves[p].hash
+        } else {
+            self.nodes[self.node_at(h, p)].as_ref().unwrap()
         }
-        self.nodes[i] = d.finalize();
     }
 
+    // The index of the node at position `p` in the level of nodes `h` levels
+    // above the leaves.
+    fn node_at(&self, h: usize, p: usize) -> usize {
+        self.nodes.len() / K.pow(h as u32) + p
+    }
+
+    // The bottom level of nodes starts at `nodes.len() / K`, since every node
+    // above it has `K` children and there is one more node than that.
     fn leaf_parent(&self, i: usize) -> usize {
-        self.nodes.len() / 2 + i / 2 - 1
+        self.nodes.len() / K + i / K
     }
 
     fn node_parent(&self, i: usize) -> usize {
-        (i - 1) / 2
+        (i - 1) / K
     }
 
     fn left_child_node(&self, i: usize) -> usize {
-        i * 2 + 1
+        i * K + 1
     }
 
     fn left_child_leaf(&self, i: usize) -> usize {
-        (i - self.nodes.len() / 2) * 2
+        (i - self.nodes.len() / K) * K
+    }
+}
+
+// Multiproofs are only made for binary trees.
+impl<D: Digest, T, S: Scheme<D>> MerkleTree<D, T, S> {
+    pub fn multiproof(&self, indices: &[usize]) -> MultiProof<D, S> {
+        let mut known = indices.to_vec();
+        known.sort_unstable();
+        known.dedup();
+        let n = self.leaves.len();
+        assert!(
+            known.last().map_or(true, |&i| i < n),
+            "leaf index out of range"
+        );
+        // Work up one level at a time, as the verifier will, adding the
+        // siblings of known positions that it has no other way to compute.
+        let mut hashes = Vec::new();
+        let (mut h, mut width) = (0, 1);
+        while width < n {
+            let mut parents = Vec::with_capacity(known.len());
+            let mut k = 0;
+            while k < known.len() {
+                let p = known[k];
+                if p % 2 == 0 && known.get(k + 1) == Some(&(p + 1)) {
+                    k += 1;
+                } else if (p ^ 1) * width < n {
+                    hashes.push(self.node_ref(h, p ^ 1).clone());
+                }
+                parents.push(p / 2);
+                k += 1;
+            }
+            known = parents;
+            h += 1;
+            width *= 2;
+        }
+        MultiProof::new(hashes)
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>, const K: usize> FromIterator<Leaf<D, T>>
+    for MerkleTree<D, T, S, K>
+{
+    fn from_iter<I: IntoIterator<Item = Leaf<D, T>>>(iter: I) -> Self {
+        Self::from_leaves(iter.into_iter().collect())
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>, const K: usize> Extend<Leaf<D, T>> for MerkleTree<D, T, S, K> {
+    fn extend<I: IntoIterator<Item = Leaf<D, T>>>(&mut self, iter: I) {
+        let n = self.leaves.len();
+        self.leaves.extend(iter);
+        let l = node_count::<K>(self.leaves.len()).expect("too many leaves");
+        if l != self.nodes.len() {
+            // The tree has new levels, so every node moves.
+            self.nodes.resize(l, None);
+            self.rehash_all();
+        } else {
+            self.rehash_leaves(n, self.leaves.len());
+        }
+    }
+}
+
+// The number of nodes above `n` leaves when each node has `K` children: enough
+// for a perfect tree over the smallest power of `K` that holds them all. This
+// is `None` if that power does not fit in a `usize`.
+pub(crate) fn node_count<const K: usize>(n: usize) -> Option<usize> {
+    let mut width: usize = 1;
+    while width < n {
+        width = width.checked_mul(K)?;
     }
+    Some((width - 1) / (K - 1))
 }
 
-impl<D: Digest, T> Default for MerkleTree<D, T> {
+// The level of the root of a tree with `n` leaves and `K` children per node,
+// or `None` if, as for `node_count`, the tree would be too wide.
+pub(crate) fn height<const K: usize>(n: usize) -> Option<usize> {
+    let (mut h, mut width): (usize, usize) = (0, 1);
+    while width < n {
+        width = width.checked_mul(K)?;
+        h += 1;
+    }
+    Some(h)
+}
+
+impl<D: Digest, T, S: Scheme<D>, const K: usize> Default for MerkleTree<D, T, S, K> {
     fn default() -> Self {
         Self::new()
     }
@@ -129,8 +514,276 @@ impl<D: Digest, T> Default for MerkleTree<D, T> {
 
 #[cfg(test)]
 mod tests {
+    use crate::testing::leaf;
+    use crate::{
+        node_count, Bitcoin, Digest, IndexError, Leaf, Legacy, MerkleTree, Output, Rfc6962, Scheme,
+        Sorted,
+    };
+    use proptest::prelude::*;
+    use sha2::Sha256;
+
+    #[test]
+    fn accessors() {
+        let tree: MerkleTree<_, _, Rfc6962> = (0..5).map(leaf).collect();
+        assert_eq!(tree.len(), 5);
+        assert!(!tree.is_empty());
+        assert_eq!(tree.leaf(3).map(|l| l.data), Some(3));
+        assert!(tree.leaf(5).is_none());
+        assert!(tree.leaves().map(|l| l.data).eq(0..5));
+        assert_eq!(tree.depth(), 3);
+        assert_eq!(tree.level(0).count(), 5);
+        assert_eq!(tree.level(1).count(), 3);
+        assert_eq!(tree.level(2).count(), 2);
+        assert_eq!(tree.level(3).collect::<Vec<_>>(), vec![&tree.hash()]);
+        assert_eq!(tree.level(4).count(), 0);
+        assert_eq!(tree.node_hash(0, 4), Some(&leaf(4).hash));
+        assert_eq!(tree.node_hash(3, 0), Some(&tree.hash()));
+        assert_eq!(tree.node_hash(1, 3), None);
+        assert_eq!(tree.node_hash(4, 0), None);
+
+        // A lone leaf is hashed by the scheme to give the root.
+        let one: MerkleTree<_, _, Rfc6962> = (0..1).map(leaf).collect();
+        assert_eq!(one.depth(), 0);
+        assert_eq!(one.level(0).collect::<Vec<_>>(), vec![&leaf(0).hash]);
+        assert_eq!(one.hash(), <Rfc6962 as Scheme<Sha256>>::leaf(&leaf(0).hash));
+
+        let empty = MerkleTree::<Sha256, usize>::new();
+        assert!(empty.is_empty());
+        assert_eq!(empty.depth(), 0);
+        assert_eq!(empty.level(0).count(), 0);
+        assert_eq!(empty.node_hash(0, 0), None);
+    }
+
+    #[test]
+    fn wide_accessors() {
+        let tree: MerkleTree<_, _, Rfc6962, 4> = (0..17).map(leaf).collect();
+        assert_eq!(tree.depth(), 3);
+        assert_eq!(tree.nodes.len(), 1 + 4 + 16);
+        assert_eq!(tree.level(1).count(), 5);
+        assert_eq!(tree.level(2).count(), 2);
+        assert_eq!(tree.level(3).collect::<Vec<_>>(), vec![&tree.hash()]);
+        assert_eq!(tree.node_hash(0, 16), Some(&leaf(16).hash));
+        assert_eq!(tree.node_hash(2, 1), tree.level(2).nth(1));
+        assert_eq!(tree.node_hash(2, 2), None);
+    }
+
+    #[test]
+    fn hashes_data() {
+        let mut tree = MerkleTree::<Sha256, &str>::new();
+        tree.push_data("b");
+        tree.insert_data(0, "a");
+        assert_eq!(tree.leaf(0).unwrap().hash, Sha256::digest(b"a"));
+        assert_eq!(tree.leaf(1).unwrap().hash, Sha256::digest(b"b"));
+        let leaf = Leaf::<Sha256, _>::from_data(vec![1u8, 2, 3]);
+        assert_eq!(leaf.hash, Sha256::digest(&[1, 2, 3]));
+    }
+
     #[test]
-    fn it_works() {
-        assert_eq!(2 + 2, 4);
+    fn fallible_mutations() {
+        let mut tree = MerkleTree::<Sha256, usize>::new();
+        assert_eq!(tree.try_remove(0).map(|l| l.data), Err(IndexError::Empty));
+        assert_eq!(
+            tree.try_replace(0, leaf(0)).map(|l| l.data),
+            Err(IndexError::Empty)
+        );
+        assert_eq!(
+            tree.try_insert(1, leaf(0)),
+            Err(IndexError::OutOfRange { index: 1, len: 0 })
+        );
+        assert_eq!(tree.try_insert(0, leaf(0)), Ok(()));
+        assert_eq!(tree.try_remove(0).map(|l| l.data), Ok(0));
+        assert_eq!(tree.try_insert(0, leaf(0)), Ok(()));
+        assert_eq!(tree.try_insert(1, leaf(2)), Ok(()));
+        assert_eq!(tree.try_insert(1, leaf(1)), Ok(()));
+        assert_eq!(
+            tree.try_replace(3, leaf(3)).map(|l| l.data),
+            Err(IndexError::OutOfRange { index: 3, len: 3 })
+        );
+        assert_eq!(
+            tree.try_remove(usize::MAX).map(|l| l.data),
+            Err(IndexError::OutOfRange {
+                index: usize::MAX,
+                len: 3
+            })
+        );
+        let expected: MerkleTree<Sha256, usize> = (0..3).map(leaf).collect();
+        assert_eq!(tree.hash(), expected.hash());
+        assert_eq!(tree.try_replace(2, leaf(3)).map(|l| l.data), Ok(2));
+        assert_eq!(tree.try_remove(0).map(|l| l.data), Ok(0));
+        let expected: MerkleTree<Sha256, usize> = [1, 3].iter().copied().map(leaf).collect();
+        assert_eq!(tree.hash(), expected.hash());
+    }
+
+    // `replace` used to rehash the empty range `j..j`, leaving the root as it
+    // was.
+    #[test]
+    fn replace_changes_root() {
+        for n in 1..20 {
+            let mut tree: MerkleTree<Sha256, usize> = (0..n).map(leaf).collect();
+            let mut hashes: Vec<_> = (0..n).map(|i| leaf(i).hash).collect();
+            for i in 0..n {
+                let before = tree.hash();
+                tree.replace(i, leaf(100 + i));
+                hashes[i] = leaf(100 + i).hash;
+                assert_ne!(tree.hash(), before, "n={} i={}", n, i);
+                assert_eq!(tree.hash(), naive::<Legacy, 2>(&hashes), "n={} i={}", n, i);
+            }
+        }
+    }
+
+    // Adding and dropping levels used to size the nodes wrongly, and inserts
+    // started rehashing from a leaf index rather than the leaf's parent.
+    #[test]
+    fn insert_and_remove_across_levels() {
+        let mut tree = MerkleTree::<Sha256, usize>::new();
+        let mut hashes = Vec::new();
+        for i in 0..33 {
+            tree.insert(0, leaf(i));
+            hashes.insert(0, leaf(i).hash);
+            assert_eq!(tree.nodes.len(), node_count::<2>(i + 1).unwrap());
+            assert_eq!(tree.hash(), naive::<Legacy, 2>(&hashes), "n={}", i + 1);
+        }
+        while !hashes.is_empty() {
+            tree.remove(0);
+            hashes.remove(0);
+            assert_eq!(tree.nodes.len(), node_count::<2>(hashes.len()).unwrap());
+            assert_eq!(tree.hash(), naive::<Legacy, 2>(&hashes));
+        }
+    }
+
+    #[test]
+    fn bulk_construction_matches_push() {
+        let mut pushed = MerkleTree::<Sha256, usize, Rfc6962>::new();
+        for n in 0..40 {
+            let built: MerkleTree<_, _, Rfc6962> = (0..n).map(leaf).collect();
+            assert_eq!(built.nodes, pushed.nodes, "n={}", n);
+            assert_eq!(built.hash(), pushed.hash());
+            pushed.push(leaf(n));
+        }
+    }
+
+    #[test]
+    fn extend_matches_push() {
+        for (a, b) in [(0, 1), (1, 1), (3, 1), (3, 2), (4, 9), (5, 2), (9, 30)] {
+            let mut extended: MerkleTree<_, _, Rfc6962> = (0..a).map(leaf).collect();
+            extended.extend((a..a + b).map(leaf));
+            let built: MerkleTree<_, _, Rfc6962> = (0..a + b).map(leaf).collect();
+            assert_eq!(extended.nodes, built.nodes, "a={} b={}", a, b);
+        }
+    }
+
+    // The root of `hashes` computed from scratch: pad the leaves out to a power
+    // of `K` and group them up level by level.
+    fn naive<S: Scheme<Sha256>, const K: usize>(hashes: &[Output<Sha256>]) -> Output<Sha256> {
+        let mut width = 1;
+        while width < hashes.len() {
+            width *= K;
+        }
+        let mut level: Vec<_> = (0..width)
+            .map(|i| hashes.get(i).map(|h| S::leaf(h)))
+            .collect();
+        while level.len() > 1 {
+            level = level
+                .chunks(K)
+                .map(|c| S::node(&c.iter().flatten().collect::<Vec<_>>()))
+                .collect();
+        }
+        level.pop().flatten().unwrap_or_else(S::empty)
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Push(u8),
+        Insert(usize, u8),
+        Remove(usize),
+        SwapRemove(usize),
+        Replace(usize, u8),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            any::<u8>().prop_map(Op::Push),
+            (any::<usize>(), any::<u8>()).prop_map(|(i, v)| Op::Insert(i, v)),
+            any::<usize>().prop_map(Op::Remove),
+            any::<usize>().prop_map(Op::SwapRemove),
+            (any::<usize>(), any::<u8>()).prop_map(|(i, v)| Op::Replace(i, v)),
+        ]
+    }
+
+    // Apply `ops` to a tree and to a plain list of leaf hashes, checking the
+    // root against the list after every step. Indices wrap around the current
+    // size, and operations that need a leaf are skipped on an empty tree.
+    fn check<S: Scheme<Sha256>, const K: usize>(ops: Vec<Op>) -> Result<(), TestCaseError> {
+        let mut tree = MerkleTree::<Sha256, u8, S, K>::new();
+        let mut model: Vec<Output<Sha256>> = Vec::new();
+        let new = |v: u8| Leaf::new(Sha256::digest(&[v]), v);
+        for op in ops {
+            let n = model.len();
+            match op {
+                Op::Push(v) => {
+                    tree.push(new(v));
+                    model.push(new(v).hash);
+                }
+                Op::Insert(i, v) => {
+                    tree.insert(i % (n + 1), new(v));
+                    model.insert(i % (n + 1), new(v).hash);
+                }
+                Op::Remove(_) | Op::SwapRemove(_) | Op::Replace(..) if n == 0 => continue,
+                Op::Remove(i) => {
+                    prop_assert_eq!(tree.remove(i % n).hash, model.remove(i % n));
+                }
+                Op::SwapRemove(i) => {
+                    prop_assert_eq!(tree.swap_remove(i % n).hash, model.swap_remove(i % n));
+                }
+                Op::Replace(i, v) => {
+                    let old = std::mem::replace(&mut model[i % n], new(v).hash);
+                    prop_assert_eq!(tree.replace(i % n, new(v)).hash, old);
+                }
+            }
+            prop_assert_eq!(tree.len(), model.len());
+            prop_assert_eq!(tree.hash(), naive::<S, K>(&model));
+        }
+        // Padding past the last leaf is never rehashed, so it must still match
+        // a tree built from scratch.
+        let built: MerkleTree<Sha256, u8, S, K> = model.iter().map(|h| Leaf::new(*h, 0)).collect();
+        prop_assert_eq!(tree.nodes, built.nodes);
+        Ok(())
+    }
+
+    proptest! {
+        #[test]
+        fn legacy_matches_model(ops in prop::collection::vec(op(), 0..80)) {
+            check::<Legacy, 2>(ops)?;
+        }
+
+        #[test]
+        fn rfc6962_matches_model(ops in prop::collection::vec(op(), 0..80)) {
+            check::<Rfc6962, 2>(ops)?;
+        }
+
+        #[test]
+        fn bitcoin_matches_model(ops in prop::collection::vec(op(), 0..80)) {
+            check::<Bitcoin, 2>(ops)?;
+        }
+
+        #[test]
+        fn sorted_matches_model(ops in prop::collection::vec(op(), 0..80)) {
+            check::<Sorted, 2>(ops)?;
+        }
+
+        #[test]
+        fn ternary_matches_model(ops in prop::collection::vec(op(), 0..80)) {
+            check::<Rfc6962, 3>(ops)?;
+        }
+
+        #[test]
+        fn quaternary_matches_model(ops in prop::collection::vec(op(), 0..80)) {
+            check::<Legacy, 4>(ops)?;
+        }
+
+        #[test]
+        fn sixteen_ary_matches_model(ops in prop::collection::vec(op(), 0..80)) {
+            check::<Bitcoin, 16>(ops)?;
+        }
     }
 }
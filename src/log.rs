@@ -0,0 +1,273 @@
+use crate::scheme::{node, Rfc6962, Scheme};
+use crate::{Leaf, MerkleTree, Proof};
+use digest::{Digest, Output};
+use std::vec::Vec;
+
+/// An append-only Merkle tree hashed as in RFC 6962, which can prove that any
+/// earlier version of itself is a prefix of a later one.
+///
+/// The tree shape, node hashing and proofs follow the RFC, but each entry of
+/// the log is the `hash` of a `Leaf`, hashed again with the 0x00 prefix (see
+/// `Rfc6962`). A `Log` holding the same data as a Certificate Transparency
+/// log does not have the same root.
+pub struct Log<D: Digest, T> {
+    tree: MerkleTree<D, T, Rfc6962>,
+}
+
+/// A consistency proof between two sizes of a `Log`, as in section 2.1.4 of
+/// RFC 9162.
+pub struct ConsistencyProof<D: Digest> {
+    pub hashes: Vec<Output<D>>,
+}
+
+impl<D: Digest, T> Log<D, T> {
+    pub fn new() -> Self {
+        Self {
+            tree: MerkleTree::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tree.is_empty()
+    }
+
+    pub fn push(&mut self, leaf: Leaf<D, T>) {
+        self.tree.push(leaf);
+    }
+
+    /// The current root hash.
+    pub fn hash(&self) -> Output<D> {
+        self.tree.hash()
+    }
+
+    /// The root hash the log had when it held `n` leaves.
+    pub fn root(&self, n: usize) -> Output<D> {
+        assert!(n <= self.len(), "tree size out of range");
+        if n == 0 {
+            <Rfc6962 as Scheme<D>>::empty()
+        } else {
+            self.subtree(0, n)
+        }
+    }
+
+    /// An inclusion proof for leaf `i` in the log as it was with `n` leaves.
+    pub fn proof(&self, i: usize, n: usize) -> Proof<D, Rfc6962> {
+        assert!(i < n && n <= self.len(), "leaf index out of range");
+        self.path(i, n)
+    }
+
+    /// A proof that the log with `m` leaves is a prefix of the log with `n`.
+    pub fn consistency(&self, m: usize, n: usize) -> ConsistencyProof<D> {
+        assert!(m <= n && n <= self.len(), "tree size out of range");
+        let mut hashes = Vec::new();
+        if 0 < m && m < n {
+            self.subproof(m, 0, n, true, &mut hashes);
+        }
+        ConsistencyProof { hashes }
+    }
+
+    fn subproof(&self, m: usize, a: usize, b: usize, whole: bool, out: &mut Vec<Output<D>>) {
+        if m == b - a {
+            if !whole {
+                out.push(self.subtree(a, b));
+            }
+            return;
+        }
+        let k = (b - a).next_power_of_two() / 2;
+        if m <= k {
+            self.subproof(m, a, a + k, whole, out);
+            out.push(self.subtree(a + k, b));
+        } else {
+            self.subproof(m - k, a + k, b, false, out);
+            out.push(self.subtree(a, a + k));
+        }
+    }
+}
+
+// A list of leaves hashed as in RFC 6962 that keeps the hashes of some of its
+// subtrees, from which it can give the root of, and inclusion proofs for, any
+// prefix of itself.
+pub(crate) trait Subtrees<D: Digest> {
+    // The hash of leaf `i`, as it was added.
+    fn leaf_hash(&self, i: usize) -> &Output<D>;
+
+    // The hash of leaves a..b, if it can be had without combining smaller
+    // ranges.
+    fn stored(&self, a: usize, b: usize) -> Option<Output<D>>;
+
+    // The hash of leaves a..b, where a is a multiple of the smallest power of
+    // two that is at least b - a, as every range that RFC 6962 splits into is.
+    fn subtree(&self, a: usize, b: usize) -> Output<D> {
+        if let Some(hash) = self.stored(a, b) {
+            return hash;
+        }
+        let k = (b - a).next_power_of_two() / 2;
+        node::<D, Rfc6962>(&self.subtree(a, a + k), &self.subtree(a + k, b))
+    }
+
+    // An inclusion proof for leaf `i` among the first `n` leaves.
+    fn path(&self, i: usize, n: usize) -> Proof<D, Rfc6962> {
+        let mut siblings = Vec::new();
+        if i ^ 1 < n {
+            siblings.push(self.leaf_hash(i ^ 1).clone());
+        }
+        let (mut j, mut width) = (i / 2, 2);
+        while width < n {
+            let s = j ^ 1;
+            if s * width < n {
+                siblings.push(self.subtree(s * width, n.min((s + 1) * width)));
+            }
+            j /= 2;
+            width *= 2;
+        }
+        Proof::new(siblings)
+    }
+}
+
+impl<D: Digest, T> Subtrees<D> for Log<D, T> {
+    fn leaf_hash(&self, i: usize) -> &Output<D> {
+        &self.tree.leaves[i].hash
+    }
+
+    fn stored(&self, a: usize, b: usize) -> Option<Output<D>> {
+        let w = (b - a).next_power_of_two();
+        if w == 1 {
+            return Some(<Rfc6962 as Scheme<D>>::leaf(&self.tree.leaves[a].hash));
+        }
+        if b != self.len().min(a + w) {
+            return None;
+        }
+        // This range is exactly what one of the tree's nodes covers.
+        let k = (self.tree.nodes.len() + 1) / w - 1 + a / w;
+        self.tree.nodes[k].clone()
+    }
+}
+
+impl<D: Digest, T> Default for Log<D, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: Digest> ConsistencyProof<D> {
+    /// Check that `old` is the root of a log with `m` leaves and that `new` is
+    /// the root of the same log after growing to `n` leaves.
+    pub fn verify(&self, m: usize, n: usize, old: &Output<D>, new: &Output<D>) -> bool {
+        if m > n {
+            return false;
+        }
+        if m == 0 {
+            return self.hashes.is_empty() && *old == <Rfc6962 as Scheme<D>>::empty();
+        }
+        if m == n {
+            return self.hashes.is_empty() && old == new;
+        }
+        let mut hashes = self.hashes.iter();
+        let first = if m.is_power_of_two() {
+            old.clone()
+        } else {
+            match hashes.next() {
+                Some(h) => h.clone(),
+                None => return false,
+            }
+        };
+        let (mut f, mut s) = (m - 1, n - 1);
+        while f & 1 == 1 {
+            f >>= 1;
+            s >>= 1;
+        }
+        let (mut fr, mut sr) = (first.clone(), first);
+        for c in hashes {
+            if s == 0 {
+                return false;
+            }
+            if f & 1 == 1 || f == s {
+                fr = node::<D, Rfc6962>(c, &fr);
+                sr = node::<D, Rfc6962>(c, &sr);
+                while f & 1 == 0 && f != 0 {
+                    f >>= 1;
+                    s >>= 1;
+                }
+            } else {
+                sr = node::<D, Rfc6962>(&sr, c);
+            }
+            f >>= 1;
+            s >>= 1;
+        }
+        fr == *old && sr == *new && s == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Log;
+    use crate::testing::leaf;
+    use crate::{Digest, MerkleTree, Rfc6962};
+    use sha2::Sha256;
+
+    fn log(n: usize) -> Log<Sha256, usize> {
+        let mut log = Log::new();
+        for i in 0..n {
+            log.push(leaf(i));
+        }
+        log
+    }
+
+    #[test]
+    fn historical_roots() {
+        let log = log(21);
+        let mut tree = MerkleTree::<Sha256, usize, Rfc6962>::new();
+        for n in 0..=21 {
+            assert_eq!(log.root(n), tree.hash(), "n={}", n);
+            tree.push(leaf(n));
+        }
+    }
+
+    #[test]
+    fn consistency_proofs_verify() {
+        let log = log(21);
+        for n in 0..=21 {
+            for m in 0..=n {
+                let proof = log.consistency(m, n);
+                let (old, new) = (log.root(m), log.root(n));
+                assert!(proof.verify(m, n, &old, &new), "m={} n={}", m, n);
+                if 0 < m && m < n {
+                    assert!(!proof.verify(m, n, &new, &old));
+                    assert!(!proof.verify(m, n, &log.root(m - 1), &new));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn tampered_consistency_proof_fails() {
+        let log = log(13);
+        let (old, new) = (log.root(6), log.root(13));
+        let mut proof = log.consistency(6, 13);
+        proof.hashes[1] = Sha256::digest(b"forged");
+        assert!(!proof.verify(6, 13, &old, &new));
+        proof.hashes.truncate(1);
+        assert!(!proof.verify(6, 13, &old, &new));
+    }
+
+    #[test]
+    fn historical_inclusion_proofs() {
+        let log = log(19);
+        for n in 1..=19 {
+            let root = log.root(n);
+            for i in 0..n {
+                let hash = Sha256::digest(&i.to_le_bytes());
+                assert!(
+                    log.proof(i, n).verify(&root, &hash, i, n),
+                    "n={} i={}",
+                    n,
+                    i
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,617 @@
+use digest::{Digest, Output};
+use std::cmp::Ordering;
+use std::ops::{Bound, RangeBounds};
+use std::vec::Vec;
+
+/// A map from byte-string keys to values, kept as a Merkle treap: a binary
+/// search tree on the keys' bytes whose nodes each hold one entry and are
+/// hashed over their left subtree, their entry and their right subtree. Each
+/// node sits above every node with a smaller key digest, so the shape, and
+/// with it the root hash, depends only on the contents, and the tree is
+/// O(log n) deep in expectation. A write rehashes only the nodes on one path.
+///
+/// The map can prove that a key is present with a given value, that it is
+/// absent, or exactly which entries fall in a range of keys. An empty map, and
+/// an empty subtree, hashes to all zeroes.
+pub struct MerkleMap<D: Digest, K, V> {
+    root: Link<D, K, V>,
+    len: usize,
+}
+
+type Link<D, K, V> = Option<Box<Node<D, K, V>>>;
+
+struct Node<D: Digest, K, V> {
+    key: K,
+    value: V,
+    // The digest of the key. Each node's is greater than those below it.
+    priority: Output<D>,
+    entry: Output<D>,
+    hash: Output<D>,
+    left: Link<D, K, V>,
+    right: Link<D, K, V>,
+}
+
+/// A proof of the entries of a `MerkleMap` with keys in some range: the nodes
+/// that a search for those keys visits, in pre-order, with every subtree that
+/// holds no keys in the range cut down to its hash.
+pub struct MapProof<D: Digest> {
+    pub nodes: Vec<MapProofNode<D>>,
+}
+
+pub enum MapProofNode<D: Digest> {
+    /// A key and its value, followed in the proof by the node's left subtree
+    /// and then its right subtree.
+    Entry(Vec<u8>, Vec<u8>),
+    /// The hash of a subtree with no keys in the range.
+    Subtree(Output<D>),
+    /// An empty subtree.
+    Empty,
+}
+
+// The hash of an entry: the key's length as a big-endian u64, the key, then
+// the value.
+fn entry_hash<D: Digest>(key: &[u8], value: &[u8]) -> Output<D> {
+    D::new()
+        .chain((key.len() as u64).to_be_bytes())
+        .chain(key)
+        .chain(value)
+        .finalize()
+}
+
+fn node_hash<D: Digest>(left: &Output<D>, entry: &Output<D>, right: &Output<D>) -> Output<D> {
+    D::new().chain(left).chain(entry).chain(right).finalize()
+}
+
+fn link_hash<D: Digest, K, V>(link: &Link<D, K, V>) -> Output<D> {
+    link.as_ref()
+        .map_or_else(Output::<D>::default, |n| n.hash.clone())
+}
+
+fn above(bound: Bound<&[u8]>, key: &[u8]) -> bool {
+    match bound {
+        Bound::Included(b) => key < b,
+        Bound::Excluded(b) => key <= b,
+        Bound::Unbounded => false,
+    }
+}
+
+fn below(bound: Bound<&[u8]>, key: &[u8]) -> bool {
+    match bound {
+        Bound::Included(b) => key > b,
+        Bound::Excluded(b) => key >= b,
+        Bound::Unbounded => false,
+    }
+}
+
+// Whether every key strictly between `lo` and `hi` falls outside `range`. An
+// excluded start bound is treated as included, which only makes proofs a node
+// larger; the prover and the verifier must agree on it, not be exact.
+fn outside<R: RangeBounds<[u8]>>(range: &R, lo: Option<&[u8]>, hi: Option<&[u8]>) -> bool {
+    let before = match (range.start_bound(), hi) {
+        (Bound::Included(b) | Bound::Excluded(b), Some(hi)) => hi <= b,
+        _ => false,
+    };
+    let after = match (range.end_bound(), lo) {
+        (Bound::Included(b) | Bound::Excluded(b), Some(lo)) => lo >= b,
+        _ => false,
+    };
+    before || after
+}
+
+impl<D: Digest, K: AsRef<[u8]>, V: AsRef<[u8]>> Node<D, K, V> {
+    fn new(key: K, value: V) -> Box<Self> {
+        let entry = entry_hash::<D>(key.as_ref(), value.as_ref());
+        let empty = Output::<D>::default();
+        Box::new(Node {
+            priority: D::digest(key.as_ref()),
+            hash: node_hash::<D>(&empty, &entry, &empty),
+            key,
+            value,
+            entry,
+            left: None,
+            right: None,
+        })
+    }
+
+    fn rehash(&mut self) {
+        self.hash = node_hash::<D>(&link_hash(&self.left), &self.entry, &link_hash(&self.right));
+    }
+}
+
+// Put `new` into the tree at `link`, returning the value it replaces.
+fn insert<D, K, V>(link: &mut Link<D, K, V>, mut new: Box<Node<D, K, V>>) -> Option<V>
+where
+    D: Digest,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let node = match link {
+        Some(node) => node,
+        None => {
+            *link = Some(new);
+            return None;
+        }
+    };
+    let old = match new.key.as_ref().cmp(node.key.as_ref()) {
+        Ordering::Equal => {
+            node.entry = new.entry;
+            Some(std::mem::replace(&mut node.value, new.value))
+        }
+        // The key is not in this subtree, or it would be above `node`.
+        _ if new.priority > node.priority => {
+            let (left, right) = split(link.take(), new.key.as_ref());
+            new.left = left;
+            new.right = right;
+            new.rehash();
+            *link = Some(new);
+            return None;
+        }
+        Ordering::Less => insert(&mut node.left, new),
+        Ordering::Greater => insert(&mut node.right, new),
+    };
+    node.rehash();
+    old
+}
+
+// Split the tree at `link` into the keys before `key` and those after it.
+fn split<D, K, V>(link: Link<D, K, V>, key: &[u8]) -> (Link<D, K, V>, Link<D, K, V>)
+where
+    D: Digest,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let mut node = match link {
+        Some(node) => node,
+        None => return (None, None),
+    };
+    if node.key.as_ref() < key {
+        let (left, right) = split(node.right.take(), key);
+        node.right = left;
+        node.rehash();
+        (Some(node), right)
+    } else {
+        let (left, right) = split(node.left.take(), key);
+        node.left = right;
+        node.rehash();
+        (left, Some(node))
+    }
+}
+
+// Join two trees, where every key in `left` is before every key in `right`.
+fn merge<D, K, V>(left: Link<D, K, V>, right: Link<D, K, V>) -> Link<D, K, V>
+where
+    D: Digest,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let (mut left, mut right) = match (left, right) {
+        (Some(left), Some(right)) => (left, right),
+        (left, None) => return left,
+        (None, right) => return right,
+    };
+    if left.priority > right.priority {
+        left.right = merge(left.right.take(), Some(right));
+        left.rehash();
+        Some(left)
+    } else {
+        right.left = merge(Some(left), right.left.take());
+        right.rehash();
+        Some(right)
+    }
+}
+
+// Take `key` out of the tree at `link`, returning its value.
+fn remove<D, K, V>(link: &mut Link<D, K, V>, key: &[u8]) -> Option<V>
+where
+    D: Digest,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+{
+    let node = link.as_mut()?;
+    let value = match key.cmp(node.key.as_ref()) {
+        Ordering::Less => remove(&mut node.left, key)?,
+        Ordering::Greater => remove(&mut node.right, key)?,
+        Ordering::Equal => {
+            let mut node = link.take().unwrap();
+            *link = merge(node.left.take(), node.right.take());
+            return Some(node.value);
+        }
+    };
+    node.rehash();
+    Some(value)
+}
+
+// Add the part of the tree at `link` that a search for `range` visits to
+// `nodes`, where every key in the tree is strictly between `lo` and `hi`.
+fn prove<D, K, V, R>(
+    link: &Link<D, K, V>,
+    lo: Option<&[u8]>,
+    hi: Option<&[u8]>,
+    range: &R,
+    nodes: &mut Vec<MapProofNode<D>>,
+) where
+    D: Digest,
+    K: AsRef<[u8]>,
+    V: AsRef<[u8]>,
+    R: RangeBounds<[u8]>,
+{
+    match link {
+        None => nodes.push(MapProofNode::Empty),
+        Some(node) if outside(range, lo, hi) => {
+            nodes.push(MapProofNode::Subtree(node.hash.clone()))
+        }
+        Some(node) => {
+            let key = node.key.as_ref();
+            nodes.push(MapProofNode::Entry(
+                key.to_vec(),
+                node.value.as_ref().to_vec(),
+            ));
+            prove(&node.left, lo, Some(key), range, nodes);
+            prove(&node.right, Some(key), hi, range, nodes);
+        }
+    }
+}
+
+// An in-order walk of the entries, from the first one not before a bound.
+struct Iter<'a, D: Digest, K, V> {
+    // The nodes whose entries and right subtrees are still to come, the next
+    // one last.
+    stack: Vec<&'a Node<D, K, V>>,
+}
+
+impl<'a, D: Digest, K: AsRef<[u8]>, V> Iter<'a, D, K, V> {
+    fn new(mut link: Option<&'a Node<D, K, V>>, start: Bound<&[u8]>) -> Self {
+        let mut stack = Vec::new();
+        while let Some(node) = link {
+            if above(start, node.key.as_ref()) {
+                link = node.right.as_deref();
+            } else {
+                stack.push(node);
+                link = node.left.as_deref();
+            }
+        }
+        Iter { stack }
+    }
+}
+
+impl<'a, D: Digest, K, V> Iterator for Iter<'a, D, K, V> {
+    type Item = (&'a K, &'a V);
+
+    fn next(&mut self) -> Option<(&'a K, &'a V)> {
+        let node = self.stack.pop()?;
+        let mut link = node.right.as_deref();
+        while let Some(next) = link {
+            self.stack.push(next);
+            link = next.left.as_deref();
+        }
+        Some((&node.key, &node.value))
+    }
+}
+
+impl<D: Digest, K: AsRef<[u8]>, V: AsRef<[u8]>> MerkleMap<D, K, V> {
+    pub fn new() -> Self {
+        Self { root: None, len: 0 }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn hash(&self) -> Output<D> {
+        link_hash(&self.root)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&V> {
+        let mut link = self.root.as_deref();
+        while let Some(node) = link {
+            link = match key.cmp(node.key.as_ref()) {
+                Ordering::Less => node.left.as_deref(),
+                Ordering::Greater => node.right.as_deref(),
+                Ordering::Equal => return Some(&node.value),
+            };
+        }
+        None
+    }
+
+    pub fn contains_key(&self, key: &[u8]) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Set the value for `key`, returning the one it replaces.
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        let old = insert(&mut self.root, Node::new(key, value));
+        if old.is_none() {
+            self.len += 1;
+        }
+        old
+    }
+
+    /// Remove `key`, returning its value.
+    pub fn remove(&mut self, key: &[u8]) -> Option<V> {
+        let value = remove(&mut self.root, key)?;
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// The entries in order of their keys.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        Iter::new(self.root.as_deref(), Bound::Unbounded)
+    }
+
+    /// The entries with keys in `range`, in order.
+    pub fn range<R: RangeBounds<[u8]>>(&self, range: R) -> impl Iterator<Item = (&K, &V)> {
+        Iter::new(self.root.as_deref(), range.start_bound())
+            .take_while(move |(k, _)| !below(range.end_bound(), k.as_ref()))
+    }
+
+    /// A proof of the value for `key`, or that there is none.
+    pub fn proof(&self, key: &[u8]) -> MapProof<D> {
+        self.range_proof((Bound::Included(key), Bound::Included(key)))
+    }
+
+    /// A proof of the entries with keys in `range`.
+    pub fn range_proof<R: RangeBounds<[u8]>>(&self, range: R) -> MapProof<D> {
+        let mut nodes = Vec::new();
+        prove(&self.root, None, None, &range, &mut nodes);
+        MapProof { nodes }
+    }
+}
+
+impl<D: Digest, K: AsRef<[u8]>, V: AsRef<[u8]>> Default for MerkleMap<D, K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// An entry of a `MapProof` whose left subtree has been checked, or is being.
+struct Frame<'a, D: Digest> {
+    key: &'a [u8],
+    value: &'a [u8],
+    // The bound on the keys of the entry's right subtree.
+    hi: Option<&'a [u8]>,
+    left: Option<Output<D>>,
+}
+
+impl<D: Digest> MapProof<D> {
+    /// Check that `value` is the value for `key` in a map with the given
+    /// `root`, or with `None` that there is no such key.
+    pub fn verify(&self, root: &Output<D>, key: &[u8], value: Option<&[u8]>) -> bool {
+        match self.verify_range(root, (Bound::Included(key), Bound::Included(key))) {
+            Some(found) => match (&found[..], value) {
+                ([], None) => true,
+                ([(k, v)], Some(value)) => *k == key && *v == value,
+                _ => false,
+            },
+            None => false,
+        }
+    }
+
+    /// Check the proof against a map with the given `root`, and return the
+    /// entries it holds with keys in `range`, which are all the map has, in
+    /// order. Returns `None` if the proof does not check out.
+    pub fn verify_range<R: RangeBounds<[u8]>>(
+        &self,
+        root: &Output<D>,
+        range: R,
+    ) -> Option<Vec<(&[u8], &[u8])>> {
+        // Walk the nodes in pre-order without recursing, since the proof may
+        // be as deep as it is long. `stack` holds the entries above the next
+        // node, which must have a key between `lo` and `hi`.
+        let mut stack: Vec<Frame<'_, D>> = Vec::new();
+        let (mut lo, mut hi): (Option<&[u8]>, Option<&[u8]>) = (None, None);
+        let mut found = Vec::new();
+        let mut nodes = self.nodes.iter();
+        loop {
+            let mut hash = match nodes.next()? {
+                MapProofNode::Entry(key, value) => {
+                    if lo.is_some_and(|lo| key[..] <= *lo) || hi.is_some_and(|hi| key[..] >= *hi) {
+                        return None;
+                    }
+                    stack.push(Frame {
+                        key,
+                        value,
+                        hi,
+                        left: None,
+                    });
+                    hi = Some(key);
+                    continue;
+                }
+                MapProofNode::Subtree(hash) if outside(&range, lo, hi) => hash.clone(),
+                MapProofNode::Subtree(_) => return None,
+                MapProofNode::Empty => Output::<D>::default(),
+            };
+            // A subtree is done: hand its hash up to the entries above it.
+            loop {
+                let frame = match stack.last_mut() {
+                    Some(frame) => frame,
+                    None => return (nodes.next().is_none() && hash == *root).then_some(found),
+                };
+                if frame.left.is_none() {
+                    frame.left = Some(hash);
+                    if !above(range.start_bound(), frame.key)
+                        && !below(range.end_bound(), frame.key)
+                    {
+                        found.push((frame.key, frame.value));
+                    }
+                    lo = Some(frame.key);
+                    hi = frame.hi;
+                    break;
+                }
+                let frame = stack.pop().unwrap();
+                let entry = entry_hash::<D>(frame.key, frame.value);
+                hash = node_hash::<D>(frame.left.as_ref().unwrap(), &entry, &hash);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{entry_hash, node_hash, Link, MapProofNode, MerkleMap};
+    use proptest::prelude::*;
+    use sha2::Sha256;
+    use std::collections::BTreeMap;
+    use std::ops::Bound;
+
+    fn map(keys: &[u32]) -> MerkleMap<Sha256, Vec<u8>, String> {
+        let mut map = MerkleMap::new();
+        for &k in keys {
+            map.insert(k.to_be_bytes().to_vec(), format!("value {}", k));
+        }
+        map
+    }
+
+    fn key(k: u32) -> [u8; 4] {
+        k.to_be_bytes()
+    }
+
+    fn depth<K, V>(link: &Link<Sha256, K, V>) -> usize {
+        link.as_ref()
+            .map_or(0, |n| 1 + depth(&n.left).max(depth(&n.right)))
+    }
+
+    #[test]
+    fn map_operations() {
+        let mut m = map(&[50, 10, 40, 20, 30]);
+        assert_eq!(m.len(), 5);
+        assert!(m
+            .iter()
+            .map(|(k, _)| k.clone())
+            .eq([10u32, 20, 30, 40, 50].iter().map(|k| key(*k).to_vec())));
+        assert_eq!(m.get(&key(30)).map(String::as_str), Some("value 30"));
+        assert!(m.get(&key(35)).is_none());
+        assert_eq!(
+            m.insert(key(30).to_vec(), "new".into()).as_deref(),
+            Some("value 30")
+        );
+        assert_eq!(m.remove(&key(10)).as_deref(), Some("value 10"));
+        assert!(m.remove(&key(10)).is_none());
+        assert_eq!(m.len(), 4);
+        let range: Vec<_> = m
+            .range::<(Bound<&[u8]>, Bound<&[u8]>)>((
+                Bound::Included(&key(20)),
+                Bound::Excluded(&key(40)),
+            ))
+            .map(|(_, v)| v.as_str())
+            .collect();
+        assert_eq!(range, ["value 20", "new"]);
+
+        // The root depends only on the contents.
+        let mut other = map(&[20, 40, 50]);
+        other.insert(key(30).to_vec(), "new".into());
+        assert_eq!(other.hash(), m.hash());
+    }
+
+    #[test]
+    fn stays_shallow() {
+        let m = map(&(0..4096).collect::<Vec<_>>());
+        assert!(depth(&m.root) <= 40, "depth {}", depth(&m.root));
+    }
+
+    #[test]
+    fn membership_proofs() {
+        let m = map(&[10, 20, 30, 40, 50, 60, 70]);
+        let root = m.hash();
+        for k in (5..80).step_by(5) {
+            let proof = m.proof(&key(k));
+            if k % 10 == 0 {
+                let value = format!("value {}", k);
+                assert!(
+                    proof.verify(&root, &key(k), Some(value.as_bytes())),
+                    "{}",
+                    k
+                );
+                assert!(!proof.verify(&root, &key(k), Some(b"other")));
+                assert!(!proof.verify(&root, &key(k), None));
+            } else {
+                assert!(proof.verify(&root, &key(k), None), "{}", k);
+                assert!(!proof.verify(&root, &key(k), Some(b"")));
+            }
+        }
+
+        // Cutting an entry down to the hash of its subtree keeps the root, but
+        // would hide the entry.
+        let mut proof = m.proof(&key(30));
+        let i = proof
+            .nodes
+            .iter()
+            .position(|n| matches!(n, MapProofNode::Entry(k, _) if k[..] == key(30)))
+            .unwrap();
+        let children: Vec<_> = proof.nodes[i + 1..i + 3]
+            .iter()
+            .map(|n| match n {
+                MapProofNode::Subtree(hash) => *hash,
+                MapProofNode::Empty => Default::default(),
+                MapProofNode::Entry(..) => unreachable!(),
+            })
+            .collect();
+        let entry = entry_hash::<Sha256>(&key(30), b"value 30");
+        let hash = node_hash::<Sha256>(&children[0], &entry, &children[1]);
+        proof.nodes.splice(i..i + 3, [MapProofNode::Subtree(hash)]);
+        assert!(!proof.verify(&root, &key(30), None));
+
+        let empty = map(&[]);
+        assert!(empty.proof(&key(1)).verify(&empty.hash(), &key(1), None));
+    }
+
+    #[test]
+    fn range_proofs() {
+        let m = map(&[10, 20, 30, 40, 50, 60, 70]);
+        let root = m.hash();
+        for (lo, hi, expected) in [
+            (15, 45, 3),
+            (0, 100, 7),
+            (20, 20, 1),
+            (21, 29, 0),
+            (70, 90, 1),
+        ] {
+            let range = (Bound::Included(&key(lo)[..]), Bound::Included(&key(hi)[..]));
+            let proof = m.range_proof(range);
+            let found = proof.verify_range(&root, range).unwrap();
+            assert_eq!(found.len(), expected, "{}..={}", lo, hi);
+            assert!(found
+                .iter()
+                .all(|(k, _)| k[..] >= key(lo)[..] && k[..] <= key(hi)[..]));
+
+            // Checked against a wider range, the proof either fails or
+            // still gives every entry in it.
+            if lo > 0 {
+                let wider = (
+                    Bound::Included(&key(lo - 10)[..]),
+                    Bound::Included(&key(hi)[..]),
+                );
+                if let Some(found) = proof.verify_range(&root, wider) {
+                    assert_eq!(found.len(), m.range(wider).count());
+                }
+            }
+        }
+    }
+
+    proptest! {
+        // Any mix of writes leaves the same map, and the same root, as
+        // inserting the final contents into an empty map.
+        #[test]
+        fn matches_model(ops in prop::collection::vec((any::<u8>(), any::<bool>()), 0..200)) {
+            let mut m = MerkleMap::<Sha256, Vec<u8>, Vec<u8>>::new();
+            let mut model = BTreeMap::new();
+            for (k, insert) in ops {
+                if insert {
+                    prop_assert_eq!(m.insert(vec![k], vec![k, 1]), model.insert(vec![k], vec![k, 1]));
+                } else {
+                    prop_assert_eq!(m.remove(&[k]), model.remove(&vec![k]));
+                }
+            }
+            prop_assert_eq!(m.len(), model.len());
+            prop_assert!(m.iter().eq(model.iter()));
+            let mut built = MerkleMap::<Sha256, Vec<u8>, Vec<u8>>::new();
+            for (k, v) in &model {
+                built.insert(k.clone(), v.clone());
+            }
+            prop_assert_eq!(m.hash(), built.hash());
+        }
+    }
+}
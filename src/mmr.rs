@@ -0,0 +1,166 @@
+use crate::log::Subtrees;
+use crate::proof::Proof;
+use crate::scheme::{node, Rfc6962, Scheme};
+use crate::Leaf;
+use digest::{Digest, Output};
+use std::vec::Vec;
+
+/// A Merkle mountain range: an append-only list of leaves kept as a row of
+/// perfect binary trees, the peaks, of strictly decreasing size. Appending a
+/// leaf hashes at most one node per level, as it merges peaks of equal size,
+/// and never rehashes anything already stored.
+///
+/// The root bags the peaks from right to left. With nodes hashed as in RFC
+/// 6962 this gives the same root as a `Log` or a `MerkleTree<D, T, Rfc6962>`
+/// over the same leaves, and the same inclusion proofs.
+pub struct Mmr<D: Digest, T> {
+    leaves: Vec<Leaf<D, T>>,
+    // levels[h][k] is the hash of the perfect subtree over leaves
+    // k * 2^h .. (k + 1) * 2^h. Once written, it never changes.
+    levels: Vec<Vec<Output<D>>>,
+}
+
+impl<D: Digest, T> Mmr<D, T> {
+    pub fn new() -> Self {
+        Self {
+            leaves: Vec::new(),
+            levels: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn leaf(&self, i: usize) -> Option<&Leaf<D, T>> {
+        self.leaves.get(i)
+    }
+
+    pub fn push(&mut self, leaf: Leaf<D, T>) {
+        let mut hash = <Rfc6962 as Scheme<D>>::leaf(&leaf.hash);
+        self.leaves.push(leaf);
+        // Merge the new peak with the one to its left while they are the same
+        // size.
+        for h in 0.. {
+            if h == self.levels.len() {
+                self.levels.push(Vec::new());
+            }
+            let level = &mut self.levels[h];
+            level.push(hash);
+            if level.len() % 2 == 1 {
+                break;
+            }
+            hash = node::<D, Rfc6962>(&level[level.len() - 2], &level[level.len() - 1]);
+        }
+    }
+
+    /// The current root hash.
+    pub fn hash(&self) -> Output<D> {
+        self.root(self.len())
+    }
+
+    /// The peaks of the range as it was with `n` leaves, from left to right.
+    pub fn peaks(&self, n: usize) -> Vec<&Output<D>> {
+        assert!(n <= self.len(), "tree size out of range");
+        (0..self.levels.len())
+            .rev()
+            .filter(|h| n >> h & 1 == 1)
+            .map(|h| &self.levels[h][(n >> h) - 1])
+            .collect()
+    }
+
+    /// The root hash the range had when it held `n` leaves.
+    pub fn root(&self, n: usize) -> Output<D> {
+        let mut peaks = self.peaks(n).into_iter().rev();
+        match peaks.next() {
+            Some(last) => peaks.fold(last.clone(), |bag, peak| node::<D, Rfc6962>(peak, &bag)),
+            None => <Rfc6962 as Scheme<D>>::empty(),
+        }
+    }
+
+    /// An inclusion proof for leaf `i` in the range as it was with `n` leaves.
+    pub fn proof(&self, i: usize, n: usize) -> Proof<D, Rfc6962> {
+        assert!(i < n && n <= self.len(), "leaf index out of range");
+        self.path(i, n)
+    }
+}
+
+impl<D: Digest, T> Subtrees<D> for Mmr<D, T> {
+    fn leaf_hash(&self, i: usize) -> &Output<D> {
+        &self.leaves[i].hash
+    }
+
+    // Every whole subtree is kept. Ranges that are not one bag the peaks they
+    // cover.
+    fn stored(&self, a: usize, b: usize) -> Option<Output<D>> {
+        let w = b - a;
+        if !w.is_power_of_two() {
+            return None;
+        }
+        let h = w.trailing_zeros() as usize;
+        Some(self.levels[h][a >> h].clone())
+    }
+}
+
+impl<D: Digest, T> Default for Mmr<D, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Mmr;
+    use crate::testing::leaf;
+    use crate::Log;
+    use sha2::Sha256;
+
+    #[test]
+    fn matches_log() {
+        let mut mmr = Mmr::<Sha256, usize>::new();
+        let mut log = Log::<Sha256, usize>::new();
+        assert_eq!(mmr.hash(), log.hash());
+        for i in 0..40 {
+            mmr.push(leaf(i));
+            log.push(leaf(i));
+            assert_eq!(mmr.hash(), log.hash(), "n={}", i + 1);
+        }
+        for n in 0..=40 {
+            assert_eq!(mmr.root(n), log.root(n));
+            assert_eq!(mmr.peaks(n).len(), n.count_ones() as usize);
+        }
+        assert_eq!(mmr.leaf(7).map(|l| l.data), Some(7));
+    }
+
+    #[test]
+    fn historical_proofs() {
+        let mut mmr = Mmr::<Sha256, usize>::new();
+        for i in 0..33 {
+            mmr.push(leaf(i));
+        }
+        for n in 1..=33 {
+            let root = mmr.root(n);
+            for i in 0..n {
+                let proof = mmr.proof(i, n);
+                assert!(proof.verify(&root, &leaf(i).hash, i, n), "i={} n={}", i, n);
+                assert!(!proof.verify(&root, &leaf(i + 1).hash, i, n));
+            }
+        }
+    }
+
+    #[test]
+    fn appends_touch_few_nodes() {
+        let mut mmr = Mmr::<Sha256, usize>::new();
+        for i in 0..1000 {
+            mmr.push(leaf(i));
+        }
+        // Each level holds one node for every full subtree of its size.
+        for (h, level) in mmr.levels.iter().enumerate() {
+            assert_eq!(level.len(), 1000 >> h);
+        }
+    }
+}
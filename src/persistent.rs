@@ -0,0 +1,341 @@
+use crate::proof::Proof;
+use crate::scheme::{pair, Legacy, Scheme};
+use crate::Leaf;
+use digest::{Digest, Output};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::vec::Vec;
+
+/// An immutable Merkle tree. Every update returns a new version of the tree
+/// that shares all the nodes off the updated path with the old one, so
+/// keeping many versions around costs only the paths that changed.
+///
+/// The tree has the same shape and root as a `MerkleTree` over the same
+/// leaves, and its proofs verify with `Proof::verify`. Leaves can only be
+/// added and removed at the end, since anything else would move every leaf
+/// after them.
+pub struct PersistentTree<D: Digest, T, S: Scheme<D> = Legacy> {
+    root: Option<Arc<Node<D, T>>>,
+    len: usize,
+    // The root is this many levels above the leaves.
+    height: usize,
+    // The hash of a subtree of each height that covers no leaves.
+    empty: Arc<Vec<Option<Output<D>>>>,
+    scheme: PhantomData<S>,
+}
+
+enum Node<D: Digest, T> {
+    Leaf {
+        hash: Output<D>,
+        leaf: Leaf<D, T>,
+    },
+    // Children that cover no leaves are left out.
+    Branch {
+        hash: Option<Output<D>>,
+        left: Option<Arc<Node<D, T>>>,
+        right: Option<Arc<Node<D, T>>>,
+    },
+}
+
+impl<D: Digest, T> Node<D, T> {
+    fn hash(&self) -> Option<&Output<D>> {
+        match self {
+            Node::Leaf { hash, .. } => Some(hash),
+            Node::Branch { hash, .. } => hash.as_ref(),
+        }
+    }
+
+    fn children(&self) -> (Option<&Arc<Self>>, Option<&Arc<Self>>) {
+        match self {
+            Node::Leaf { .. } => (None, None),
+            Node::Branch { left, right, .. } => (left.as_ref(), right.as_ref()),
+        }
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>> PersistentTree<D, T, S> {
+    pub fn new() -> Self {
+        Self {
+            root: None,
+            len: 0,
+            height: 0,
+            empty: Arc::new(vec![None]),
+            scheme: PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn hash(&self) -> Output<D> {
+        self.root
+            .as_ref()
+            .and_then(|root| root.hash().cloned())
+            .unwrap_or_else(S::empty)
+    }
+
+    pub fn leaf(&self, i: usize) -> Option<&Leaf<D, T>> {
+        if i >= self.len {
+            return None;
+        }
+        let mut node = self.root.as_ref()?;
+        for h in (0..self.height).rev() {
+            let (left, right) = node.children();
+            node = if i >> h & 1 == 0 { left? } else { right? };
+        }
+        match &**node {
+            Node::Leaf { leaf, .. } => Some(leaf),
+            Node::Branch { .. } => None,
+        }
+    }
+
+    /// A new version of the tree with `leaf` added at the end.
+    pub fn push(&self, leaf: Leaf<D, T>) -> Self {
+        let mut tree = self.clone();
+        if tree.len > 0 && tree.len == 1 << tree.height {
+            // The tree is full, so the old root becomes the left half of a new
+            // level.
+            if tree.empty.len() == tree.height + 1 {
+                let mut empty = (*tree.empty).clone();
+                let e = &empty[tree.height];
+                empty.push(pair::<D, S>(e.as_ref(), e.as_ref()));
+                tree.empty = Arc::new(empty);
+            }
+            tree.height += 1;
+            let root = tree.root.take();
+            tree.root = Some(tree.branch(tree.height, root, None));
+        }
+        tree.root = Some(tree.set(tree.root.as_ref(), tree.height, tree.len, leaf));
+        tree.len += 1;
+        tree
+    }
+
+    /// A new version of the tree without its last leaf.
+    pub fn pop(&self) -> Self {
+        let mut tree = self.clone();
+        if tree.len == 0 {
+            return tree;
+        }
+        tree.len -= 1;
+        let root = tree.root.take();
+        tree.root = root.and_then(|root| tree.clear(&root, tree.height, tree.len));
+        // Drop levels that the remaining leaves no longer need.
+        while tree.height > 0 && tree.len <= 1 << (tree.height - 1) {
+            tree.root = tree.root.and_then(|root| root.children().0.cloned());
+            tree.height -= 1;
+        }
+        tree
+    }
+
+    /// A new version of the tree with leaf `i` replaced by `leaf`.
+    pub fn replace(&self, i: usize, leaf: Leaf<D, T>) -> Self {
+        assert!(i < self.len, "leaf index out of range");
+        let mut tree = self.clone();
+        tree.root = Some(tree.set(self.root.as_ref(), self.height, i, leaf));
+        tree
+    }
+
+    pub fn proof(&self, i: usize) -> Proof<D, S> {
+        assert!(i < self.len, "leaf index out of range");
+        let mut siblings = Vec::with_capacity(self.height);
+        let mut node = self.root.as_ref();
+        for h in (0..self.height).rev() {
+            let (left, right) = node.unwrap().children();
+            let (next, sibling) = if i >> h & 1 == 0 {
+                (left, right)
+            } else {
+                (right, left)
+            };
+            // Siblings that cover no leaves are padding, which the verifier
+            // recomputes. Leaves are given by their own hash.
+            if let Some(sibling) = sibling {
+                siblings.push(match &**sibling {
+                    Node::Leaf { leaf, .. } => leaf.hash.clone(),
+                    Node::Branch { hash, .. } => hash.clone().unwrap(),
+                });
+            }
+            node = next;
+        }
+        siblings.reverse();
+        Proof::new(siblings)
+    }
+
+    // A copy of the subtree `node` of height `h`, with leaf `i` of it set to
+    // `leaf`.
+    fn set(
+        &self,
+        node: Option<&Arc<Node<D, T>>>,
+        h: usize,
+        i: usize,
+        leaf: Leaf<D, T>,
+    ) -> Arc<Node<D, T>> {
+        if h == 0 {
+            return Arc::new(Node::Leaf {
+                hash: S::leaf(&leaf.hash),
+                leaf,
+            });
+        }
+        let (left, right) = node.map_or((None, None), |n| n.children());
+        let (mut left, mut right) = (left.cloned(), right.cloned());
+        let half = 1 << (h - 1);
+        if i < half {
+            left = Some(self.set(left.as_ref(), h - 1, i, leaf));
+        } else {
+            right = Some(self.set(right.as_ref(), h - 1, i - half, leaf));
+        }
+        self.branch(h, left, right)
+    }
+
+    // A copy of the subtree `node` of height `h` without leaf `i` of it, which
+    // must be its last. Returns `None` if no leaves are left.
+    fn clear(&self, node: &Arc<Node<D, T>>, h: usize, i: usize) -> Option<Arc<Node<D, T>>> {
+        if h == 0 {
+            return None;
+        }
+        let (left, right) = node.children();
+        let half = 1 << (h - 1);
+        let (left, right) = if i < half {
+            (left.and_then(|n| self.clear(n, h - 1, i)), None)
+        } else {
+            (
+                left.cloned(),
+                right.and_then(|n| self.clear(n, h - 1, i - half)),
+            )
+        };
+        if left.is_none() && right.is_none() {
+            return None;
+        }
+        Some(self.branch(h, left, right))
+    }
+
+    fn branch(
+        &self,
+        h: usize,
+        left: Option<Arc<Node<D, T>>>,
+        right: Option<Arc<Node<D, T>>>,
+    ) -> Arc<Node<D, T>> {
+        let empty = self.empty[h - 1].as_ref();
+        let hash = pair::<D, S>(
+            left.as_ref().map_or(empty, |n| n.hash()),
+            right.as_ref().map_or(empty, |n| n.hash()),
+        );
+        Arc::new(Node::Branch { hash, left, right })
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>> Clone for PersistentTree<D, T, S> {
+    fn clone(&self) -> Self {
+        Self {
+            root: self.root.clone(),
+            len: self.len,
+            height: self.height,
+            empty: self.empty.clone(),
+            scheme: PhantomData,
+        }
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>> Default for PersistentTree<D, T, S> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Node, PersistentTree};
+    use crate::testing::leaf;
+    use crate::{Bitcoin, Legacy, MerkleTree, Rfc6962, Scheme, Sorted};
+    use sha2::Sha256;
+    use std::sync::Arc;
+
+    fn matches_merkle_tree<S: Scheme<Sha256>>() {
+        let mut versions = vec![PersistentTree::<Sha256, usize, S>::new()];
+        let mut tree = MerkleTree::<Sha256, usize, S>::new();
+        for i in 0..40 {
+            let next = versions[i].push(leaf(i));
+            tree.push(leaf(i));
+            assert_eq!(next.hash(), tree.hash(), "n={}", i + 1);
+            versions.push(next);
+        }
+        // Old versions are unchanged.
+        for (n, version) in versions.iter().enumerate() {
+            assert_eq!(version.len(), n);
+            let expected: MerkleTree<_, _, S> = (0..n).map(leaf).collect();
+            assert_eq!(version.hash(), expected.hash());
+        }
+        // Dropping a level and growing it again gives the same tree.
+        let regrown = versions[17].pop().pop().push(leaf(15)).push(leaf(16));
+        assert_eq!(regrown.push(leaf(17)).hash(), versions[18].hash());
+        let mut version = versions.pop().unwrap();
+        for i in (0..40).rev() {
+            version = version.replace(i / 2, leaf(100 + i)).pop();
+            tree.replace(i / 2, leaf(100 + i));
+            tree.remove(i);
+            assert_eq!(version.hash(), tree.hash(), "n={}", i);
+        }
+        assert!(version.is_empty());
+    }
+
+    #[test]
+    fn legacy_matches_merkle_tree() {
+        matches_merkle_tree::<Legacy>();
+    }
+
+    #[test]
+    fn rfc6962_matches_merkle_tree() {
+        matches_merkle_tree::<Rfc6962>();
+    }
+
+    #[test]
+    fn bitcoin_matches_merkle_tree() {
+        matches_merkle_tree::<Bitcoin>();
+    }
+
+    #[test]
+    fn sorted_matches_merkle_tree() {
+        matches_merkle_tree::<Sorted>();
+    }
+
+    #[test]
+    fn proofs_and_lookups() {
+        let mut tree = PersistentTree::<Sha256, usize, Rfc6962>::new();
+        for n in 1..20 {
+            tree = tree.push(leaf(n - 1));
+            for i in 0..n {
+                assert_eq!(tree.leaf(i).map(|l| l.data), Some(i));
+                let proof = tree.proof(i);
+                assert!(
+                    proof.verify(&tree.hash(), &leaf(i).hash, i, n),
+                    "{}/{}",
+                    i,
+                    n
+                );
+            }
+            assert!(tree.leaf(n).is_none());
+        }
+    }
+
+    #[test]
+    fn versions_share_subtrees() {
+        let old = (0..8).fold(PersistentTree::<Sha256, usize>::new(), |t, i| {
+            t.push(leaf(i))
+        });
+        let new = old.replace(6, leaf(60));
+        let (old_left, new_left) = match (old.root.as_deref(), new.root.as_deref()) {
+            (Some(Node::Branch { left: a, .. }), Some(Node::Branch { left: b, .. })) => {
+                (a.clone().unwrap(), b.clone().unwrap())
+            }
+            _ => unreachable!(),
+        };
+        assert!(Arc::ptr_eq(&old_left, &new_left));
+        assert_ne!(old.hash(), new.hash());
+        assert_eq!(old.leaf(6).map(|l| l.data), Some(6));
+        assert_eq!(new.leaf(6).map(|l| l.data), Some(60));
+    }
+}
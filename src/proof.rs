@@ -0,0 +1,360 @@
+use crate::scheme::{combine, pair, Legacy, Scheme};
+use crate::{Leaf, LeafHash};
+use digest::{Digest, Output};
+use std::marker::PhantomData;
+use std::vec::Vec;
+
+/// An inclusion proof for a single leaf of a `MerkleTree` with `K` children
+/// per node.
+///
+/// `siblings` holds the hashes needed to rebuild the path from the leaf to the
+/// root, bottom-up and left to right within a level. Siblings that cover no
+/// leaves are left out, since the verifier can recompute them from the tree
+/// size alone.
+pub struct Proof<D: Digest, S: Scheme<D> = Legacy, const K: usize = 2> {
+    pub siblings: Vec<Output<D>>,
+    scheme: PhantomData<S>,
+}
+
+impl<D: Digest, S: Scheme<D>, const K: usize> Proof<D, S, K> {
+    pub fn new(siblings: Vec<Output<D>>) -> Self {
+        Self {
+            siblings,
+            scheme: PhantomData,
+        }
+    }
+
+    /// Check that `leaf` is the hash at index `i` of a tree with `n` leaves
+    /// and the given `root`. Trees have at least two children per node, so
+    /// this is always false if `K` is less than two.
+    pub fn verify(&self, root: &Output<D>, leaf: &Output<D>, i: usize, n: usize) -> bool {
+        if K < 2 || i >= n {
+            return false;
+        }
+        let mut siblings = self.siblings.iter();
+        let mut hash = Some(S::leaf(leaf));
+
+        // Rebuild each node on the path from its children. Those past the last
+        // leaf are padding: absent at the bottom, and nodes over nothing but
+        // padding above that.
+        let mut pad = None;
+        let (mut j, mut width) = (i, 1);
+        while width < n {
+            let first = j - j % K;
+            let mut children: [Option<Output<D>>; K] = std::array::from_fn(|_| None);
+            for (c, child) in children.iter_mut().enumerate() {
+                *child = if first + c == j {
+                    hash.take()
+                } else if (first + c).checked_mul(width).is_some_and(|w| w < n) {
+                    match siblings.next() {
+                        Some(s) if width == 1 => Some(S::leaf(s)),
+                        Some(s) => Some(s.clone()),
+                        None => return false,
+                    }
+                } else {
+                    pad.clone()
+                };
+            }
+            hash = combine::<D, S, K>(std::array::from_fn(|c| children[c].as_ref()));
+            pad = combine::<D, S, K>(std::array::from_fn(|_| pad.as_ref()));
+            j /= K;
+            width = match width.checked_mul(K) {
+                Some(width) => width,
+                None => return false,
+            };
+        }
+        siblings.next().is_none() && hash.as_ref() == Some(root)
+    }
+}
+
+/// An inclusion proof for any number of leaves of a `MerkleTree` at once.
+///
+/// `hashes` holds, level by level from the leaves up and left to right within
+/// a level, the siblings that cannot be computed from the proven leaves.
+pub struct MultiProof<D: Digest, S: Scheme<D> = Legacy> {
+    pub hashes: Vec<Output<D>>,
+    scheme: PhantomData<S>,
+}
+
+impl<D: Digest, S: Scheme<D>> MultiProof<D, S> {
+    pub fn new(hashes: Vec<Output<D>>) -> Self {
+        Self {
+            hashes,
+            scheme: PhantomData,
+        }
+    }
+
+    /// Check that each `(i, hash)` in `leaves` is the hash at index `i` of a
+    /// tree with `n` leaves and the given `root`.
+    pub fn verify(&self, root: &Output<D>, leaves: &[(usize, Output<D>)], n: usize) -> bool {
+        let mut known: Vec<(usize, Option<Output<D>>)> = leaves
+            .iter()
+            .map(|(i, hash)| (*i, Some(S::leaf(hash))))
+            .collect();
+        known.sort_by_key(|(i, _)| *i);
+        known.dedup();
+        if known.is_empty() || known.windows(2).any(|w| w[0].0 == w[1].0) {
+            return false;
+        }
+        if known[known.len() - 1].0 >= n {
+            return false;
+        }
+        let mut hashes = self.hashes.iter();
+        let mut pad = None;
+        let (mut h, mut width) = (0, 1);
+        while width < n {
+            let mut parents = Vec::with_capacity(known.len());
+            let mut level = known.into_iter().peekable();
+            while let Some((p, hash)) = level.next() {
+                let sibling = if p % 2 == 0 && level.peek().map(|k| k.0) == Some(p + 1) {
+                    level.next().unwrap().1
+                } else if (p ^ 1).checked_mul(width).is_some_and(|w| w < n) {
+                    match hashes.next() {
+                        Some(s) if h == 0 => Some(S::leaf(s)),
+                        Some(s) => Some(s.clone()),
+                        None => return false,
+                    }
+                } else {
+                    pad.clone()
+                };
+                let parent = if p % 2 == 0 {
+                    pair::<D, S>(hash.as_ref(), sibling.as_ref())
+                } else {
+                    pair::<D, S>(sibling.as_ref(), hash.as_ref())
+                };
+                parents.push((p / 2, parent));
+            }
+            known = parents;
+            pad = pair::<D, S>(pad.as_ref(), pad.as_ref());
+            h += 1;
+            width = match width.checked_mul(2) {
+                Some(width) => width,
+                None => return false,
+            };
+        }
+        hashes.next().is_none() && known[0].1.as_ref() == Some(root)
+    }
+}
+
+/// A proof that leaves `index - 1` and `index` of a `MerkleTree` are next to
+/// each other, with `None` standing for the ends of the tree. In a
+/// `SortedTree`, this shows that no leaf sorts between the two.
+///
+/// The two leaves carry their data as `T` for trees kept in order of a key
+/// taken from it, and carry only their hashes, with `()` for data, for trees
+/// kept in order of leaf hashes.
+pub struct GapProof<D: Digest, S: Scheme<D> = Legacy, T = ()> {
+    pub index: usize,
+    pub left: Option<Leaf<D, T>>,
+    pub right: Option<Leaf<D, T>>,
+    pub proof: MultiProof<D, S>,
+}
+
+impl<D: Digest, S: Scheme<D>, T> GapProof<D, S, T> {
+    /// Check that `left` and `right` are the leaves either side of `index` in
+    /// a tree with `n` leaves and the given `root`.
+    pub fn verify(&self, root: &Output<D>, n: usize) -> bool {
+        if self.index > n || self.left.is_some() != (self.index > 0) {
+            return false;
+        }
+        if self.right.is_some() != (self.index < n) {
+            return false;
+        }
+        if n == 0 {
+            return self.proof.hashes.is_empty() && *root == S::empty();
+        }
+        let mut leaves = Vec::with_capacity(2);
+        leaves.extend(self.left.as_ref().map(|l| (self.index - 1, l.hash.clone())));
+        leaves.extend(self.right.as_ref().map(|l| (self.index, l.hash.clone())));
+        self.proof.verify(root, &leaves, n)
+    }
+
+    /// Check the proof, and that `hash` sorts strictly between `left` and
+    /// `right`, so is not a leaf of a tree kept in order of leaf hashes.
+    pub fn verify_absent(&self, root: &Output<D>, n: usize, hash: &Output<D>) -> bool {
+        self.left.as_ref().map_or(true, |left| left.hash < *hash)
+            && self.right.as_ref().map_or(true, |right| *hash < right.hash)
+            && self.verify(root, n)
+    }
+}
+
+impl<D: Digest, S: Scheme<D>, T: LeafHash<D>> GapProof<D, S, T> {
+    /// Check the proof, and that `key` sorts strictly between the keys `f`
+    /// gives for the data of `left` and `right`, so is not the key of a leaf
+    /// in a tree kept in that order. The hash of each of the two leaves must
+    /// be the `LeafHash` of its data, which is what ties the data to the root.
+    pub fn verify_absent_by<K: Ord, F: FnMut(&T) -> K>(
+        &self,
+        root: &Output<D>,
+        n: usize,
+        key: &K,
+        mut f: F,
+    ) -> bool {
+        let hashed = |l: &Leaf<D, T>| l.data.leaf_hash() == l.hash;
+        self.left
+            .as_ref()
+            .map_or(true, |left| hashed(left) && f(&left.data) < *key)
+            && self
+                .right
+                .as_ref()
+                .map_or(true, |right| hashed(right) && *key < f(&right.data))
+            && self.verify(root, n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::{leaf, tree};
+    use crate::{Digest, Leaf, Legacy, MerkleTree, MultiProof, Proof, Rfc6962};
+    use sha2::digest::Output;
+    use sha2::Sha256;
+
+    #[test]
+    fn proofs_verify() {
+        for n in 1..=17 {
+            let tree = tree::<Legacy>(n);
+            let root = tree.hash();
+            for i in 0..n {
+                let leaf = Sha256::digest(&i.to_le_bytes());
+                let proof = tree.proof(i);
+                assert!(proof.verify(&root, &leaf, i, n), "n={} i={}", n, i);
+                if n > 1 {
+                    assert!(!proof.verify(&root, &leaf, (i + 1) % n, n));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn rfc6962_proofs_verify() {
+        let mut tree = MerkleTree::<Sha256, usize, Rfc6962>::new();
+        for i in 0..11usize {
+            tree.push(leaf(i));
+        }
+        let root = tree.hash();
+        for i in 0..11usize {
+            let leaf = Sha256::digest(&i.to_le_bytes());
+            assert!(tree.proof(i).verify(&root, &leaf, i, 11));
+        }
+    }
+
+    fn wide_proofs_verify<const K: usize>() {
+        for n in 1..=40 {
+            let tree: MerkleTree<Sha256, usize, Rfc6962, K> = (0..n).map(leaf).collect();
+            let root = tree.hash();
+            for i in 0..n {
+                let leaf = Sha256::digest(&i.to_le_bytes());
+                let proof = tree.proof(i);
+                assert!(proof.verify(&root, &leaf, i, n), "K={} n={} i={}", K, n, i);
+                if n > 1 {
+                    assert!(!proof.verify(&root, &leaf, (i + 1) % n, n));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn ternary_proofs_verify() {
+        wide_proofs_verify::<3>();
+    }
+
+    #[test]
+    fn sixteen_ary_proofs_verify() {
+        wide_proofs_verify::<16>();
+    }
+
+    #[test]
+    fn wide_proofs_are_shallower() {
+        let binary: MerkleTree<Sha256, usize> = (0..256usize).map(leaf).collect();
+        let wide: MerkleTree<Sha256, usize, Legacy, 16> = (0..256usize).map(leaf).collect();
+        assert_eq!(binary.depth(), 8);
+        assert_eq!(wide.depth(), 2);
+        let leaf = Sha256::digest(&100usize.to_le_bytes());
+        assert!(wide.proof(100).verify(&wide.hash(), &leaf, 100, 256));
+        assert_eq!(binary.proof(100).siblings.len(), 8);
+        assert_eq!(wide.proof(100).siblings.len(), 30);
+    }
+
+    #[test]
+    fn degenerate_arity_fails() {
+        let tree = tree::<Legacy>(3);
+        let leaf = Sha256::digest(&0usize.to_le_bytes());
+        let siblings = tree.proof(0).siblings;
+        let unary = Proof::<Sha256, Legacy, 1>::new(siblings.clone());
+        assert!(!unary.verify(&tree.hash(), &leaf, 0, 3));
+        let nullary = Proof::<Sha256, Legacy, 0>::new(siblings);
+        assert!(!nullary.verify(&tree.hash(), &leaf, 0, 3));
+    }
+
+    #[test]
+    fn oversized_trees_fail() {
+        let leaf = Sha256::digest(&0usize.to_le_bytes());
+        let siblings = vec![leaf; usize::BITS as usize];
+        let root = Sha256::digest(b"root");
+        let proof = Proof::<Sha256>::new(siblings.clone());
+        assert!(!proof.verify(&root, &leaf, 0, usize::MAX));
+        let wide = Proof::<Sha256, Legacy, 3>::new(siblings.clone());
+        assert!(!wide.verify(&root, &leaf, 0, usize::MAX));
+        let multi = MultiProof::<Sha256>::new(siblings);
+        assert!(!multi.verify(&root, &[(0, leaf)], usize::MAX));
+    }
+
+    #[test]
+    fn tampered_proof_fails() {
+        let tree = tree::<Legacy>(6);
+        let root = tree.hash();
+        let leaf = Sha256::digest(&4usize.to_le_bytes());
+        let mut proof = tree.proof(4);
+        assert!(proof.verify(&root, &leaf, 4, 6));
+        proof.siblings[0] = Sha256::digest(b"forged");
+        assert!(!proof.verify(&root, &leaf, 4, 6));
+        proof.siblings.pop();
+        assert!(!proof.verify(&root, &leaf, 4, 6));
+    }
+
+    #[test]
+    fn proofs_follow_mutations() {
+        let mut tree = tree::<Legacy>(9);
+        tree.remove(3);
+        tree.insert(0, Leaf::new(Sha256::digest(b"first"), 100));
+        tree.replace(7, Leaf::new(Sha256::digest(b"seventh"), 101));
+        let root = tree.hash();
+        let proof = tree.proof(7);
+        assert!(proof.verify(&root, &Sha256::digest(b"seventh"), 7, 9));
+        let proof = tree.proof(0);
+        assert!(proof.verify(&root, &Sha256::digest(b"first"), 0, 9));
+    }
+
+    #[test]
+    fn multiproofs_verify() {
+        for n in 1..=11 {
+            let tree = tree::<Legacy>(n);
+            let root = tree.hash();
+            for set in 1..(1u32 << n) {
+                let leaves: Vec<(usize, Output<Sha256>)> = (0..n)
+                    .filter(|i| set & (1 << i) != 0)
+                    .map(|i| (i, Sha256::digest(&i.to_le_bytes())))
+                    .collect();
+                let indices: Vec<usize> = leaves.iter().map(|(i, _)| *i).collect();
+                let proof = tree.multiproof(&indices);
+                assert!(proof.verify(&root, &leaves, n), "n={} set={:b}", n, set);
+                let mut wrong = leaves.clone();
+                wrong[0].1 = Sha256::digest(b"wrong");
+                assert!(!proof.verify(&root, &wrong, n));
+            }
+        }
+    }
+
+    #[test]
+    fn multiproof_shares_upper_levels() {
+        let tree = tree::<Legacy>(64);
+        let indices: Vec<usize> = (16..32).collect();
+        let leaves: Vec<_> = indices
+            .iter()
+            .map(|&i| (i, Sha256::digest(&i.to_le_bytes())))
+            .collect();
+        let proof = tree.multiproof(&indices);
+        assert_eq!(proof.hashes.len(), 2);
+        assert!(proof.verify(&tree.hash(), &leaves, 64));
+    }
+}
@@ -0,0 +1,260 @@
+use digest::{Digest, Output};
+
+/// How a `MerkleTree` turns leaf hashes and child nodes into node hashes.
+pub trait Scheme<D: Digest> {
+    /// Identifies the scheme in encoded trees and proofs.
+    const ID: u8;
+
+    /// Whether a node's hash depends on the order of its children. If it does
+    /// not, as for `Sorted`, trees with equal hashes can still hold their
+    /// leaves in different orders, so diffs compare every leaf.
+    const ORDERED: bool = true;
+
+    /// The hash a leaf contributes to its parent node.
+    fn leaf(hash: &Output<D>) -> Output<D>;
+
+    /// The hash of a node from its children, left to right. Children past the
+    /// last leaf are left out, so this may see fewer than the tree's arity.
+    /// Returning `None` leaves the node out of its parent in turn; this must
+    /// only happen when `children` is empty.
+    fn node(children: &[&Output<D>]) -> Option<Output<D>>;
+
+    /// The root hash of a tree with no leaves.
+    fn empty() -> Output<D>;
+}
+
+pub(crate) fn pair<D: Digest, S: Scheme<D>>(
+    left: Option<&Output<D>>,
+    right: Option<&Output<D>>,
+) -> Option<Output<D>> {
+    combine::<D, S, 2>([left, right])
+}
+
+// Hash a node from two children that are both present.
+pub(crate) fn node<D: Digest, S: Scheme<D>>(left: &Output<D>, right: &Output<D>) -> Output<D> {
+    S::node(&[left, right]).unwrap()
+}
+
+// Hash a node from its `K` children, passing on only those that are present.
+pub(crate) fn combine<D: Digest, S: Scheme<D>, const K: usize>(
+    children: [Option<&Output<D>>; K],
+) -> Option<Output<D>> {
+    let filler = Output::<D>::default();
+    let mut present = [&filler; K];
+    let mut n = 0;
+    for child in children.iter().flatten() {
+        present[n] = child;
+        n += 1;
+    }
+    S::node(&present[..n])
+}
+
+/// Plain concatenation of child hashes, with leaf hashes used as-is. This is
+/// how trees were hashed before schemes could be chosen, except that a tree of
+/// one leaf has that leaf's hash as its root, where it used to be all zeroes.
+/// It does not distinguish leaves from interior nodes. Nodes past the last
+/// leaf hash to the empty string and are still included in their parents.
+pub struct Legacy;
+
+impl<D: Digest> Scheme<D> for Legacy {
+    const ID: u8 = 0;
+
+    fn leaf(hash: &Output<D>) -> Output<D> {
+        hash.clone()
+    }
+
+    fn node(children: &[&Output<D>]) -> Option<Output<D>> {
+        let mut d = D::new();
+        for child in children {
+            d.update(child);
+        }
+        Some(d.finalize())
+    }
+
+    fn empty() -> Output<D> {
+        Output::<D>::default()
+    }
+}
+
+/// Hashing as in RFC 6962: leaves are hashed with a 0x00 prefix and interior
+/// nodes with a 0x01 prefix, so neither can pass for the other. A node with a
+/// single child takes that child's hash, which gives the tree the same shape
+/// as the RFC's.
+///
+/// The leaf hash is computed from `Leaf::hash`, not from the entry itself:
+/// a leaf is hashed as `H(0x00 || leaf.hash)`, where the RFC hashes
+/// `H(0x00 || entry)`. Roots therefore match those of an RFC 6962 log whose
+/// entries are the leaf hashes, and never those of a real log (such as a
+/// Certificate Transparency log) over the raw entries.
+pub struct Rfc6962;
+
+impl<D: Digest> Scheme<D> for Rfc6962 {
+    const ID: u8 = 1;
+
+    fn leaf(hash: &Output<D>) -> Output<D> {
+        D::new().chain([0u8]).chain(hash).finalize()
+    }
+
+    fn node(children: &[&Output<D>]) -> Option<Output<D>> {
+        match children {
+            [] => None,
+            [child] => Some((*child).clone()),
+            _ => {
+                let mut d = D::new().chain([1u8]);
+                for child in children {
+                    d.update(child);
+                }
+                Some(d.finalize())
+            }
+        }
+    }
+
+    fn empty() -> Output<D> {
+        D::new().finalize()
+    }
+}
+
+/// Bitcoin-style hashing: a node with a single child hashes that child twice.
+/// Leaves are used as-is, so `D` should be the double hash if matching Bitcoin
+/// block headers is the goal.
+pub struct Bitcoin;
+
+impl<D: Digest> Scheme<D> for Bitcoin {
+    const ID: u8 = 2;
+
+    fn leaf(hash: &Output<D>) -> Output<D> {
+        hash.clone()
+    }
+
+    fn node(children: &[&Output<D>]) -> Option<Output<D>> {
+        match children {
+            [] => None,
+            [child] => Some(D::new().chain(child).chain(child).finalize()),
+            _ => {
+                let mut d = D::new();
+                for child in children {
+                    d.update(child);
+                }
+                Some(d.finalize())
+            }
+        }
+    }
+
+    fn empty() -> Output<D> {
+        Output::<D>::default()
+    }
+}
+
+/// Sorted-pair hashing, as used by OpenZeppelin's `MerkleProof`: children are
+/// hashed in ascending order, so proofs need not say which side a sibling is
+/// on. A node with a single child takes that child's hash.
+pub struct Sorted;
+
+impl<D: Digest> Scheme<D> for Sorted {
+    const ID: u8 = 3;
+    const ORDERED: bool = false;
+
+    fn leaf(hash: &Output<D>) -> Output<D> {
+        hash.clone()
+    }
+
+    fn node(children: &[&Output<D>]) -> Option<Output<D>> {
+        match children {
+            [] => None,
+            [child] => Some((*child).clone()),
+            _ => {
+                let mut sorted = children.to_vec();
+                sorted.sort();
+                let mut d = D::new();
+                for child in sorted {
+                    d.update(child);
+                }
+                Some(d.finalize())
+            }
+        }
+    }
+
+    fn empty() -> Output<D> {
+        Output::<D>::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Bitcoin, Legacy, Rfc6962, Scheme, Sorted};
+    use crate::testing::tree;
+    use crate::{Digest, Leaf, MerkleTree, Output};
+    use sha2::Sha256;
+
+    // Build a tree of four leaves, then a tree of two leaves whose "leaf
+    // hashes" are the interior nodes of the first.
+    fn forge<S: Scheme<Sha256>>() -> bool {
+        let tree = tree::<S>(4);
+        let mut forged = MerkleTree::<Sha256, (), S>::new();
+        forged.push(Leaf::new(tree.nodes[1].unwrap(), ()));
+        forged.push(Leaf::new(tree.nodes[2].unwrap(), ()));
+        tree.hash() == forged.hash()
+    }
+
+    #[test]
+    fn legacy_is_forgeable() {
+        assert!(forge::<Legacy>());
+    }
+
+    #[test]
+    fn rfc6962_separates_leaves_from_nodes() {
+        assert!(!forge::<Rfc6962>());
+    }
+
+    // MTH from section 2.1 of RFC 6962.
+    fn mth(leaves: &[Output<Sha256>]) -> Output<Sha256> {
+        match leaves.len() {
+            0 => Sha256::digest(b""),
+            1 => Sha256::new().chain([0u8]).chain(leaves[0]).finalize(),
+            n => {
+                let k = n.next_power_of_two() / 2;
+                let (l, r) = (mth(&leaves[..k]), mth(&leaves[k..]));
+                Sha256::new().chain([1u8]).chain(l).chain(r).finalize()
+            }
+        }
+    }
+
+    #[test]
+    fn rfc6962_matches_rfc() {
+        for n in 0..=33 {
+            let leaves: Vec<_> = (0..n)
+                .map(|i: usize| Sha256::digest(&i.to_le_bytes()))
+                .collect();
+            assert_eq!(tree::<Rfc6962>(n).hash(), mth(&leaves), "n={}", n);
+        }
+    }
+
+    #[test]
+    fn bitcoin_duplicates_odd_nodes() {
+        for n in 1..=33 {
+            let mut level: Vec<_> = (0..n)
+                .map(|i: usize| Sha256::digest(&i.to_le_bytes()))
+                .collect();
+            while level.len() > 1 {
+                if level.len() % 2 == 1 {
+                    level.push(*level.last().unwrap());
+                }
+                level = level
+                    .chunks(2)
+                    .map(|c| Sha256::new().chain(c[0]).chain(c[1]).finalize())
+                    .collect();
+            }
+            assert_eq!(tree::<Bitcoin>(n).hash(), level[0], "n={}", n);
+        }
+    }
+
+    #[test]
+    fn sorted_ignores_sibling_order() {
+        let mut tree = tree::<Sorted>(5);
+        let root = tree.hash();
+        let a = tree.leaves[0].hash;
+        tree.replace(0, Leaf::new(tree.leaves[1].hash, 1));
+        tree.replace(1, Leaf::new(a, 0));
+        assert_eq!(tree.hash(), root);
+    }
+}
@@ -0,0 +1,146 @@
+use crate::scheme::Scheme;
+use crate::{Leaf, MerkleTree};
+use digest::{Digest, Output};
+use serde::de::{self, Deserialize, Deserializer, SeqAccess, Visitor};
+use serde::ser::{Serialize, SerializeStruct, Serializer};
+use std::fmt;
+use std::marker::PhantomData;
+use std::vec::Vec;
+
+struct Bytes<'a>(&'a [u8]);
+
+impl Serialize for Bytes<'_> {
+    fn serialize<Z: Serializer>(&self, s: Z) -> Result<Z::Ok, Z::Error> {
+        s.serialize_bytes(self.0)
+    }
+}
+
+impl<D: Digest, T: Serialize> Serialize for Leaf<D, T> {
+    fn serialize<Z: Serializer>(&self, s: Z) -> Result<Z::Ok, Z::Error> {
+        let mut st = s.serialize_struct("Leaf", 2)?;
+        st.serialize_field("hash", &Bytes(&self.hash))?;
+        st.serialize_field("data", &self.data)?;
+        st.end()
+    }
+}
+
+// Only the leaves are written: the nodes are rebuilt from them on load, so a
+// stored tree cannot carry node hashes that disagree with its leaves.
+impl<D: Digest, T: Serialize, S: Scheme<D>> Serialize for MerkleTree<D, T, S> {
+    fn serialize<Z: Serializer>(&self, s: Z) -> Result<Z::Ok, Z::Error> {
+        let mut st = s.serialize_struct("MerkleTree", 1)?;
+        st.serialize_field("leaves", &self.leaves)?;
+        st.end()
+    }
+}
+
+// A hash that must be exactly the output size of `D`. This accepts either
+// bytes or a sequence of integers, for formats that have no byte strings.
+struct Hash<D: Digest>(Output<D>);
+
+impl<'de, D: Digest> Deserialize<'de> for Hash<D> {
+    fn deserialize<Z: Deserializer<'de>>(d: Z) -> Result<Self, Z::Error> {
+        d.deserialize_bytes(HashVisitor::<D>(PhantomData)).map(Hash)
+    }
+}
+
+struct HashVisitor<D>(PhantomData<D>);
+
+impl<'de, D: Digest> Visitor<'de> for HashVisitor<D> {
+    type Value = Output<D>;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a {}-byte hash", D::output_size())
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        if v.len() != D::output_size() {
+            return Err(E::invalid_length(v.len(), &self));
+        }
+        let mut hash = Output::<D>::default();
+        hash.copy_from_slice(v);
+        Ok(hash)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut hash = Output::<D>::default();
+        for (i, b) in hash.iter_mut().enumerate() {
+            *b = seq
+                .next_element()?
+                .ok_or_else(|| de::Error::invalid_length(i, &self))?;
+        }
+        if seq.next_element::<u8>()?.is_some() {
+            return Err(de::Error::invalid_length(D::output_size() + 1, &self));
+        }
+        Ok(hash)
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename = "Leaf", bound = "T: Deserialize<'de>")]
+struct LeafRepr<D: Digest, T> {
+    hash: Hash<D>,
+    data: T,
+}
+
+impl<'de, D: Digest, T: Deserialize<'de>> Deserialize<'de> for Leaf<D, T> {
+    fn deserialize<Z: Deserializer<'de>>(d: Z) -> Result<Self, Z::Error> {
+        let repr = LeafRepr::<D, T>::deserialize(d)?;
+        Ok(Leaf::new(repr.hash.0, repr.data))
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename = "MerkleTree", bound = "T: Deserialize<'de>")]
+struct TreeRepr<D: Digest, T> {
+    leaves: Vec<Leaf<D, T>>,
+}
+
+impl<'de, D: Digest, T: Deserialize<'de>, S: Scheme<D>> Deserialize<'de> for MerkleTree<D, T, S> {
+    fn deserialize<Z: Deserializer<'de>>(d: Z) -> Result<Self, Z::Error> {
+        let repr = TreeRepr::<D, T>::deserialize(d)?;
+        Ok(Self::from_leaves(repr.leaves))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Digest, Leaf, MerkleTree, Rfc6962};
+    use sha2::Sha256;
+
+    fn tree(n: usize) -> MerkleTree<Sha256, String, Rfc6962> {
+        let mut tree = MerkleTree::new();
+        for i in 0..n {
+            let data = format!("leaf {}", i);
+            tree.push(Leaf::new(Sha256::digest(data.as_bytes()), data));
+        }
+        tree
+    }
+
+    #[test]
+    fn round_trip() {
+        for n in 0..=9 {
+            let tree = tree(n);
+            let json = serde_json::to_string(&tree).unwrap();
+            let loaded: MerkleTree<Sha256, String, Rfc6962> = serde_json::from_str(&json).unwrap();
+            assert_eq!(loaded.hash(), tree.hash());
+            assert_eq!(loaded.nodes, tree.nodes);
+            assert!(loaded
+                .leaves
+                .iter()
+                .map(|l| &l.data)
+                .eq(tree.leaves.iter().map(|l| &l.data)));
+        }
+    }
+
+    // Trees saved with their nodes still load, and the stored nodes are
+    // ignored in favour of the leaves.
+    #[test]
+    fn ignores_stored_nodes() {
+        let tree = tree(5);
+        let mut value = serde_json::to_value(&tree).unwrap();
+        value["nodes"] = serde_json::json!([vec![0; 32], null]);
+        let loaded: MerkleTree<Sha256, String, Rfc6962> = serde_json::from_value(value).unwrap();
+        assert_eq!(loaded.nodes, tree.nodes);
+    }
+}
@@ -0,0 +1,281 @@
+use crate::scheme::Scheme;
+use crate::{node_count, Leaf, MerkleTree};
+use digest::{Digest, Output};
+use std::ops::Deref;
+use std::vec::Vec;
+
+/// A point in the history of a `MerkleTree`, made with
+/// `MerkleTree::snapshot`. Changes made through the snapshot are applied to
+/// the tree straight away, and remember the leaves and node hashes they
+/// overwrote, so they can be undone without hashing anything. Dropping the
+/// snapshot keeps the changes.
+pub struct Snapshot<'a, D: Digest, T, S: Scheme<D>, const K: usize = 2> {
+    tree: &'a mut MerkleTree<D, T, S, K>,
+    undo: Vec<Change<D, T>>,
+    redo: Vec<Change<D, T>>,
+}
+
+// A change to the leaves, together with the node hashes to put back after it.
+struct Change<D: Digest, T> {
+    leaves: Edit<D, T>,
+    nodes: Nodes<D>,
+}
+
+enum Edit<D: Digest, T> {
+    Insert(usize, Leaf<D, T>),
+    Remove(usize),
+    // Put a leaf back where `swap_remove` took it from.
+    SwapInsert(usize, Leaf<D, T>),
+    SwapRemove(usize),
+    Replace(usize, Leaf<D, T>),
+}
+
+enum Nodes<D: Digest> {
+    // Every node, for changes that added or dropped a level.
+    All(Vec<Option<Output<D>>>),
+    // The nodes at these positions.
+    Some(Vec<(usize, Option<Output<D>>)>),
+}
+
+impl<D: Digest, T, S: Scheme<D>, const K: usize> MerkleTree<D, T, S, K> {
+    pub fn snapshot(&mut self) -> Snapshot<'_, D, T, S, K> {
+        Snapshot {
+            tree: self,
+            undo: Vec::new(),
+            redo: Vec::new(),
+        }
+    }
+
+    // Apply `change`, returning the change that undoes it.
+    fn apply(&mut self, change: Change<D, T>) -> Change<D, T> {
+        let leaves = match change.leaves {
+            Edit::Insert(i, leaf) => {
+                self.leaves.insert(i, leaf);
+                Edit::Remove(i)
+            }
+            Edit::Remove(i) => Edit::Insert(i, self.leaves.remove(i)),
+            Edit::SwapInsert(i, leaf) => {
+                self.leaves.push(leaf);
+                let last = self.leaves.len() - 1;
+                self.leaves.swap(i, last);
+                Edit::SwapRemove(i)
+            }
+            Edit::SwapRemove(i) => Edit::SwapInsert(i, self.leaves.swap_remove(i)),
+            Edit::Replace(i, leaf) => {
+                Edit::Replace(i, std::mem::replace(&mut self.leaves[i], leaf))
+            }
+        };
+        let nodes = match change.nodes {
+            Nodes::All(nodes) => Nodes::All(std::mem::replace(&mut self.nodes, nodes)),
+            Nodes::Some(mut nodes) => {
+                for (k, hash) in nodes.iter_mut() {
+                    std::mem::swap(&mut self.nodes[*k], hash);
+                }
+                Nodes::Some(nodes)
+            }
+        };
+        Change { leaves, nodes }
+    }
+
+    // The nodes that a change leaving `n` leaves behind will overwrite, where
+    // `dirty` are the bottom nodes it touches directly and `spread` says
+    // whether every later node on each level is touched too, as it is when
+    // leaves shift.
+    fn saved_nodes(&self, n: usize, dirty: &[usize], spread: bool) -> Nodes<D> {
+        if node_count::<K>(n).expect("too many leaves") != self.nodes.len() {
+            return Nodes::All(self.nodes.clone());
+        }
+        let mut saved = Vec::new();
+        if self.nodes.is_empty() {
+            return Nodes::Some(saved);
+        }
+        // Nodes past the last leaf, before or after the change, are untouched.
+        let last = self.leaf_parent(n.max(self.leaves.len()) - 1);
+        for &j in dirty {
+            let (mut start, mut end) = (j, if spread { last + 1 } else { j + 1 });
+            loop {
+                saved.extend((start..end).map(|k| (k, self.nodes[k].clone())));
+                if start == 0 {
+                    break;
+                }
+                start = self.node_parent(start);
+                end = self.node_parent(end - 1) + 1;
+            }
+        }
+        // Paths may share ancestors, and each node must be saved only once.
+        saved.sort_by_key(|&(k, _)| k);
+        saved.dedup_by_key(|&mut (k, _)| k);
+        Nodes::Some(saved)
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>, const K: usize> Snapshot<'_, D, T, S, K> {
+    pub fn insert(&mut self, i: usize, leaf: Leaf<D, T>) {
+        let n = self.tree.leaves.len();
+        assert!(i <= n, "leaf index out of range");
+        let nodes = self
+            .tree
+            .saved_nodes(n + 1, &[self.tree.leaf_parent(i)], true);
+        self.tree.insert(i, leaf);
+        self.record(Edit::Remove(i), nodes);
+    }
+
+    /// Remove leaf `i`. It is kept in case the removal is undone.
+    pub fn remove(&mut self, i: usize) {
+        let n = self.tree.leaves.len();
+        assert!(i < n, "leaf index out of range");
+        let nodes = self
+            .tree
+            .saved_nodes(n - 1, &[self.tree.leaf_parent(i)], true);
+        let leaf = self.tree.remove(i);
+        self.record(Edit::Insert(i, leaf), nodes);
+    }
+
+    /// Remove leaf `i`, moving the last leaf into its place. It is kept in
+    /// case the removal is undone.
+    pub fn swap_remove(&mut self, i: usize) {
+        let n = self.tree.leaves.len();
+        assert!(i < n, "leaf index out of range");
+        let dirty = [self.tree.leaf_parent(i), self.tree.leaf_parent(n - 1)];
+        let nodes = self.tree.saved_nodes(n - 1, &dirty, false);
+        let leaf = self.tree.swap_remove(i);
+        self.record(Edit::SwapInsert(i, leaf), nodes);
+    }
+
+    /// Replace leaf `i`. The old leaf is kept in case the replacement is
+    /// undone.
+    pub fn replace(&mut self, i: usize, leaf: Leaf<D, T>) {
+        let n = self.tree.leaves.len();
+        assert!(i < n, "leaf index out of range");
+        let nodes = self.tree.saved_nodes(n, &[self.tree.leaf_parent(i)], false);
+        let old = self.tree.replace(i, leaf);
+        self.record(Edit::Replace(i, old), nodes);
+    }
+
+    pub fn push(&mut self, leaf: Leaf<D, T>) {
+        self.insert(self.tree.leaves.len(), leaf);
+    }
+
+    /// Undo the most recent change. Returns false if there was nothing to undo.
+    pub fn undo(&mut self) -> bool {
+        match self.undo.pop() {
+            Some(change) => {
+                self.redo.push(self.tree.apply(change));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Redo the most recently undone change. Returns false if there was
+    /// nothing to redo.
+    pub fn redo(&mut self) -> bool {
+        match self.redo.pop() {
+            Some(change) => {
+                self.undo.push(self.tree.apply(change));
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Undo every change made since the snapshot was taken.
+    pub fn rollback(mut self) {
+        while self.undo() {}
+    }
+
+    /// Keep the changes. This is the same as dropping the snapshot.
+    pub fn commit(self) {}
+
+    fn record(&mut self, leaves: Edit<D, T>, nodes: Nodes<D>) {
+        self.undo.push(Change { leaves, nodes });
+        self.redo.clear();
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>, const K: usize> Deref for Snapshot<'_, D, T, S, K> {
+    type Target = MerkleTree<D, T, S, K>;
+
+    fn deref(&self) -> &MerkleTree<D, T, S, K> {
+        self.tree
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::testing::leaf;
+    use crate::{MerkleTree, Rfc6962, Snapshot};
+    use sha2::Sha256;
+
+    fn data<const K: usize>(tree: &MerkleTree<Sha256, usize, Rfc6962, K>) -> Vec<usize> {
+        tree.leaves().map(|l| l.data).collect()
+    }
+
+    // Make `count` changes of every kind to a tree of `n` leaves, checking that
+    // each one can be undone and redone.
+    fn churn<const K: usize>(
+        snapshot: &mut Snapshot<'_, Sha256, usize, Rfc6962, K>,
+        n: usize,
+        count: usize,
+    ) {
+        for k in 0..count {
+            let len = snapshot.len();
+            let (before, nodes) = (data(snapshot), snapshot.nodes.clone());
+            match k % 5 {
+                0 => snapshot.push(leaf(100 + k)),
+                1 => snapshot.insert((k * 7) % (len + 1), leaf(200 + k)),
+                _ if len == 0 => continue,
+                2 => snapshot.remove((k * 3 + n) % len),
+                3 => snapshot.swap_remove((k * 5) % len),
+                _ => snapshot.replace((k * 11) % len, leaf(300 + k)),
+            }
+            let (after, hash) = (data(snapshot), snapshot.hash());
+            let expected: MerkleTree<_, _, Rfc6962, K> = after.iter().copied().map(leaf).collect();
+            assert_eq!(snapshot.nodes, expected.nodes);
+            assert!(snapshot.undo());
+            assert_eq!(data(snapshot), before);
+            assert_eq!(snapshot.nodes, nodes);
+            assert!(snapshot.redo());
+            assert_eq!(data(snapshot), after);
+            assert_eq!(snapshot.hash(), hash);
+        }
+    }
+
+    fn restores_tree<const K: usize>() {
+        for n in 0..20 {
+            let mut tree: MerkleTree<_, _, Rfc6962, K> = (0..n).map(leaf).collect();
+            let nodes = tree.nodes.clone();
+            let mut snapshot = tree.snapshot();
+            churn(&mut snapshot, n, 3 * n + 4);
+            snapshot.rollback();
+            assert!(data(&tree).into_iter().eq(0..n), "n={}", n);
+            assert_eq!(tree.nodes, nodes, "n={}", n);
+        }
+    }
+
+    #[test]
+    fn rollback_restores_tree() {
+        restores_tree::<2>();
+    }
+
+    #[test]
+    fn wide_rollback_restores_tree() {
+        restores_tree::<3>();
+    }
+
+    #[test]
+    fn commit_keeps_changes() {
+        let mut tree: MerkleTree<_, _, Rfc6962> = (0..6).map(leaf).collect();
+        let mut snapshot = tree.snapshot();
+        snapshot.remove(0);
+        snapshot.push(leaf(6));
+        assert!(snapshot.undo());
+        snapshot.replace(0, leaf(7));
+        assert!(!snapshot.redo());
+        snapshot.commit();
+        let expected: MerkleTree<_, _, Rfc6962> =
+            [7, 2, 3, 4, 5].iter().copied().map(leaf).collect();
+        assert_eq!(tree.nodes, expected.nodes);
+        assert_eq!(tree.hash(), expected.hash());
+    }
+}
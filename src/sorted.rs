@@ -0,0 +1,289 @@
+use crate::proof::{GapProof, MultiProof};
+use crate::scheme::{Legacy, Scheme};
+use crate::{Leaf, MerkleTree};
+use digest::{Digest, Output};
+use std::iter::FromIterator;
+use std::ops::Deref;
+use std::vec::Vec;
+
+/// A `MerkleTree` whose leaves are kept in order of a key: their hash, or
+/// whatever the key function given to `by_key` takes from each leaf. The tree
+/// owns its key function and offers only changes that keep the order; it can
+/// be read like any other `MerkleTree` through `Deref`.
+pub struct SortedTree<
+    D: Digest,
+    T,
+    S: Scheme<D> = Legacy,
+    F = fn(&Leaf<D, T>) -> Output<D>,
+    const K: usize = 2,
+> {
+    tree: MerkleTree<D, T, S, K>,
+    key: F,
+}
+
+impl<D: Digest, T, S: Scheme<D>, const K: usize>
+    SortedTree<D, T, S, fn(&Leaf<D, T>) -> Output<D>, K>
+{
+    /// An empty tree kept in order of leaf hashes.
+    pub fn new() -> Self {
+        Self::by_key(|l| l.hash.clone())
+    }
+}
+
+impl<D, T, S, F, Q, const K: usize> SortedTree<D, T, S, F, K>
+where
+    D: Digest,
+    S: Scheme<D>,
+    F: Fn(&Leaf<D, T>) -> Q,
+    Q: Ord,
+{
+    /// An empty tree kept in order of the keys `key` gives for its leaves.
+    pub fn by_key(key: F) -> Self {
+        Self {
+            tree: MerkleTree::new(),
+            key,
+        }
+    }
+
+    /// Binary search for the leaf with the given key. Returns its index, or
+    /// where it would be inserted.
+    pub fn find(&self, key: &Q) -> Result<usize, usize> {
+        self.tree
+            .leaves
+            .binary_search_by(|l| (self.key)(l).cmp(key))
+    }
+
+    pub fn contains(&self, key: &Q) -> bool {
+        self.find(key).is_ok()
+    }
+
+    /// Insert `leaf` in order, returning its index. If a leaf with the same
+    /// key is already there, `leaf` is dropped and the index of the existing
+    /// one is returned as an error.
+    pub fn insert(&mut self, leaf: Leaf<D, T>) -> Result<usize, usize> {
+        let i = match self.find(&(self.key)(&leaf)) {
+            Ok(i) => return Err(i),
+            Err(i) => i,
+        };
+        self.tree.insert(i, leaf);
+        Ok(i)
+    }
+
+    /// Remove and return the leaf with the given key, if there is one.
+    pub fn remove(&mut self, key: &Q) -> Option<Leaf<D, T>> {
+        let i = self.find(key).ok()?;
+        Some(self.tree.remove(i))
+    }
+
+    pub fn into_inner(self) -> MerkleTree<D, T, S, K> {
+        self.tree
+    }
+}
+
+/// Proofs that a key is absent. Like multiproofs, which they are made of,
+/// these are only made for binary trees.
+impl<D: Digest, T, S: Scheme<D>, F> SortedTree<D, T, S, F> {
+    /// A proof that leaves `i - 1` and `i` are next to each other. Together
+    /// with the `Err(i)` from `find` on a tree kept in order of leaf hashes,
+    /// it shows that no leaf has the hash searched for.
+    pub fn gap_proof(&self, i: usize) -> GapProof<D, S> {
+        self.tree.gap(i, |_| ())
+    }
+
+    /// Like `gap_proof`, but the proof carries the data of the two leaves.
+    /// Together with the `Err(i)` from `find` on a tree kept in order of a key
+    /// taken from the data, it shows that no leaf has the key searched for;
+    /// see `GapProof::verify_absent_by`.
+    pub fn gap_proof_with_data(&self, i: usize) -> GapProof<D, S, T>
+    where
+        T: Clone,
+    {
+        self.tree.gap(i, T::clone)
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>, const K: usize> Default
+    for SortedTree<D, T, S, fn(&Leaf<D, T>) -> Output<D>, K>
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Collects leaves into a tree kept in order of leaf hashes, keeping the first
+/// of any leaves with the same hash. The tree is built in one pass.
+impl<D: Digest, T, S: Scheme<D>, const K: usize> FromIterator<Leaf<D, T>>
+    for SortedTree<D, T, S, fn(&Leaf<D, T>) -> Output<D>, K>
+{
+    fn from_iter<I: IntoIterator<Item = Leaf<D, T>>>(iter: I) -> Self {
+        let mut leaves: Vec<Leaf<D, T>> = iter.into_iter().collect();
+        leaves.sort_by(|a, b| a.hash.cmp(&b.hash));
+        leaves.dedup_by(|a, b| a.hash == b.hash);
+        Self {
+            tree: MerkleTree::from_leaves(leaves),
+            key: |l| l.hash.clone(),
+        }
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>, F, const K: usize> Deref for SortedTree<D, T, S, F, K> {
+    type Target = MerkleTree<D, T, S, K>;
+
+    fn deref(&self) -> &MerkleTree<D, T, S, K> {
+        &self.tree
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>> MerkleTree<D, T, S> {
+    // The gap proof at `i`, with the data of its leaves mapped by `data`.
+    fn gap<U>(&self, i: usize, data: impl Fn(&T) -> U) -> GapProof<D, S, U> {
+        assert!(i <= self.leaves.len(), "leaf index out of range");
+        let indices: Vec<usize> = (i.saturating_sub(1)..(i + 1).min(self.leaves.len())).collect();
+        let leaf = |l: &Leaf<D, T>| Leaf::new(l.hash.clone(), data(&l.data));
+        GapProof {
+            index: i,
+            left: i.checked_sub(1).map(|j| leaf(&self.leaves[j])),
+            right: self.leaves.get(i).map(leaf),
+            proof: if indices.is_empty() {
+                MultiProof::new(Vec::new())
+            } else {
+                self.multiproof(&indices)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SortedTree;
+    use crate::testing::leaf;
+    use crate::{Leaf, Legacy, MerkleTree, Rfc6962};
+    use sha2::Sha256;
+
+    #[test]
+    fn sorted_by_hash() {
+        let mut tree = SortedTree::<Sha256, usize, Rfc6962>::new();
+        for i in 0..30 {
+            assert!(tree.insert(leaf(i % 20)).is_ok() == (i < 20));
+        }
+        assert_eq!(tree.len(), 20);
+        assert!(tree
+            .leaves()
+            .zip(tree.leaves().skip(1))
+            .all(|(a, b)| a.hash < b.hash));
+        let mut hashes: Vec<_> = (0..20).map(|i| leaf(i).hash).collect();
+        hashes.sort();
+        let expected: MerkleTree<Sha256, _, Rfc6962> =
+            hashes.iter().map(|h| Leaf::new(*h, 0)).collect();
+        assert_eq!(tree.hash(), expected.hash());
+        let collected: SortedTree<Sha256, _, Rfc6962> = (0..30).map(|i| leaf(i % 20)).collect();
+        assert_eq!(collected.hash(), expected.hash());
+        let mut wide = SortedTree::<Sha256, usize, Rfc6962, _, 4>::new();
+        for i in (0..20).rev() {
+            wide.insert(leaf(i)).unwrap();
+        }
+        let expected: MerkleTree<Sha256, _, Rfc6962, 4> =
+            hashes.iter().map(|h| Leaf::new(*h, 0)).collect();
+        assert_eq!(wide.hash(), expected.hash());
+        for i in 0..20 {
+            let found = tree.find(&leaf(i).hash).unwrap();
+            assert_eq!(tree.leaf(found).map(|l| l.data), Some(i));
+        }
+        assert!(tree.contains(&leaf(3).hash));
+        assert!(!tree.contains(&leaf(20).hash));
+        assert_eq!(tree.remove(&leaf(3).hash).map(|l| l.data), Some(3));
+        assert!(tree.remove(&leaf(3).hash).is_none());
+        assert!(!tree.contains(&leaf(3).hash));
+    }
+
+    #[test]
+    fn sorted_by_key() {
+        let mut tree = SortedTree::<Sha256, usize, Legacy, _>::by_key(|l: &Leaf<_, usize>| l.data);
+        for i in [5, 3, 9, 1, 7, 3] {
+            let _ = tree.insert(leaf(i));
+        }
+        assert!(tree
+            .leaves()
+            .map(|l| l.data)
+            .eq([1, 3, 5, 7, 9].iter().copied()));
+        assert_eq!(tree.find(&7), Ok(3));
+        assert_eq!(tree.find(&4), Err(2));
+        assert_eq!(tree.insert(leaf(4)), Ok(2));
+        assert_eq!(tree.insert(leaf(4)), Err(2));
+        assert_eq!(tree.remove(&5).map(|l| l.data), Some(5));
+        assert_eq!(tree.find(&5), Err(3));
+        assert!(tree
+            .into_inner()
+            .leaves()
+            .map(|l| l.data)
+            .eq([1, 3, 4, 7, 9].iter().copied()));
+    }
+
+    #[test]
+    fn gap_proofs() {
+        let empty = SortedTree::<Sha256, usize, Rfc6962>::new();
+        assert!(empty
+            .gap_proof(0)
+            .verify_absent(&empty.hash(), 0, &leaf(0).hash));
+
+        let tree: SortedTree<Sha256, usize, Rfc6962> = (0..13).map(leaf).collect();
+        let (root, n) = (tree.hash(), tree.len());
+        for i in 13..40 {
+            let hash = leaf(i).hash;
+            let gap = tree.find(&hash).unwrap_err();
+            let proof = tree.gap_proof(gap);
+            assert!(proof.verify_absent(&root, n, &hash), "i={}", i);
+            if gap == n {
+                assert!(!proof.verify(&root, n + 1));
+            }
+        }
+        for i in 0..13 {
+            let hash = leaf(i).hash;
+            let at = tree.find(&hash).unwrap();
+            // Neither gap next to a leaf can hide it.
+            assert!(!tree.gap_proof(at).verify_absent(&root, n, &hash));
+            assert!(!tree.gap_proof(at + 1).verify_absent(&root, n, &hash));
+            assert!(tree.gap_proof(at).verify(&root, n));
+        }
+
+        // A gap proof for leaves that are not next to each other fails.
+        let mut proof = tree.gap_proof(5);
+        proof.right = tree.leaf(6).map(|l| Leaf::new(l.hash, ()));
+        assert!(!proof.verify(&root, n));
+        let mut proof = tree.gap_proof(0);
+        proof.right = None;
+        assert!(!proof.verify(&root, n));
+    }
+
+    #[test]
+    fn key_gap_proofs() {
+        let key = |i: usize| format!("key {:02}", i);
+        let mut tree =
+            SortedTree::<Sha256, String, Rfc6962, _>::by_key(|l: &Leaf<_, String>| l.data.clone());
+        for i in (0..24).step_by(2) {
+            tree.insert(Leaf::from_data(key(i))).unwrap();
+        }
+        let (root, n) = (tree.hash(), tree.len());
+        for i in 0..26 {
+            match tree.find(&key(i)) {
+                Err(gap) => {
+                    let proof = tree.gap_proof_with_data(gap);
+                    assert!(proof.verify_absent_by(&root, n, &key(i), String::clone));
+                }
+                Ok(at) => {
+                    for gap in [at, at + 1] {
+                        let proof = tree.gap_proof_with_data(gap);
+                        assert!(proof.verify(&root, n));
+                        assert!(!proof.verify_absent_by(&root, n, &key(i), String::clone));
+                    }
+                }
+            }
+        }
+
+        // The data must be that of the leaves in the tree.
+        let mut proof = tree.gap_proof_with_data(3);
+        proof.left.as_mut().unwrap().data = key(0);
+        assert!(proof.verify(&root, n));
+        assert!(!proof.verify_absent_by(&root, n, &key(5), String::clone));
+    }
+}
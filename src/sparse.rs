@@ -0,0 +1,274 @@
+use crate::scheme::{node, Rfc6962, Scheme};
+use crate::Leaf;
+use digest::{Digest, Output};
+use std::collections::{BTreeMap, HashMap};
+use std::vec::Vec;
+
+/// A Merkle tree with a leaf for every possible digest of a key, nearly all of
+/// them empty. Each leaf sits at the position given by the bits of its key's
+/// digest, so the tree can prove that a key is absent as well as present.
+///
+/// Nodes are hashed as in RFC 6962, and an empty leaf hashes to all zeroes.
+/// Only nodes that differ from an empty subtree of the same height are stored.
+pub struct SparseMerkleTree<D: Digest, T> {
+    // Keyed by the digest of the key.
+    leaves: BTreeMap<Output<D>, Leaf<D, T>>,
+    // Keyed by height and by the position's digest with the bits below that
+    // height cleared.
+    nodes: HashMap<(usize, Output<D>), Output<D>>,
+    // The hash of an empty subtree of each height, up to the root.
+    defaults: Vec<Output<D>>,
+}
+
+/// An inclusion or exclusion proof for a key in a `SparseMerkleTree`.
+///
+/// `siblings` holds the sibling of each node on the path from the key's leaf
+/// to the root, bottom-up, with `None` for siblings that are empty subtrees.
+pub struct SparseProof<D: Digest> {
+    pub siblings: Vec<Option<Output<D>>>,
+}
+
+fn defaults<D: Digest>() -> Vec<Output<D>> {
+    let mut defaults = vec![Output::<D>::default()];
+    for h in 0..D::output_size() * 8 {
+        defaults.push(node::<D, Rfc6962>(&defaults[h], &defaults[h]));
+    }
+    defaults
+}
+
+// Whether bit `i` of `path` is set, counting from the most significant bit of
+// the first byte, which decides the path at the root.
+fn bit<D: Digest>(path: &Output<D>, i: usize) -> bool {
+    path[i / 8] >> (7 - i % 8) & 1 == 1
+}
+
+// `path` with its last `h` bits cleared, naming the node at height `h` above
+// the leaf.
+fn prefix<D: Digest>(path: &Output<D>, h: usize) -> Output<D> {
+    let mut prefix = path.clone();
+    let bits = prefix.len() * 8;
+    for i in bits - h..bits {
+        prefix[i / 8] &= !(0x80 >> (i % 8));
+    }
+    prefix
+}
+
+// The prefix naming the sibling of the node at height `h` above the leaf at
+// `path`.
+fn sibling<D: Digest>(path: &Output<D>, h: usize) -> Output<D> {
+    let mut sibling = prefix::<D>(path, h);
+    let i = sibling.len() * 8 - 1 - h;
+    sibling[i / 8] ^= 0x80 >> (i % 8);
+    sibling
+}
+
+impl<D: Digest, T> SparseMerkleTree<D, T> {
+    pub fn new() -> Self {
+        Self {
+            leaves: BTreeMap::new(),
+            nodes: HashMap::new(),
+            defaults: defaults::<D>(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn hash(&self) -> Output<D> {
+        self.node(self.bits(), &Output::<D>::default())
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<&Leaf<D, T>> {
+        self.leaves.get(&D::digest(key))
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.leaves.contains_key(&D::digest(key))
+    }
+
+    /// Set the leaf for `key`, returning the one it replaces.
+    pub fn insert(&mut self, key: &[u8], leaf: Leaf<D, T>) -> Option<Leaf<D, T>> {
+        let path = D::digest(key);
+        let old = self.leaves.insert(path.clone(), leaf);
+        self.rehash(&path);
+        old
+    }
+
+    /// Remove and return the leaf for `key`.
+    pub fn remove(&mut self, key: &[u8]) -> Option<Leaf<D, T>> {
+        let path = D::digest(key);
+        let old = self.leaves.remove(&path)?;
+        self.rehash(&path);
+        Some(old)
+    }
+
+    /// A proof of the leaf for `key`, or that there is none.
+    pub fn proof(&self, key: &[u8]) -> SparseProof<D> {
+        let path = D::digest(key);
+        let siblings = (0..self.bits())
+            .map(|h| {
+                let hash = self.node(h, &sibling::<D>(&path, h));
+                if hash == self.defaults[h] {
+                    None
+                } else {
+                    Some(hash)
+                }
+            })
+            .collect();
+        SparseProof { siblings }
+    }
+
+    fn bits(&self) -> usize {
+        self.defaults.len() - 1
+    }
+
+    // The hash of the node at height `h` named by `prefix`.
+    fn node(&self, h: usize, prefix: &Output<D>) -> Output<D> {
+        let hash = if h == 0 {
+            self.leaves
+                .get(prefix)
+                .map(|leaf| <Rfc6962 as Scheme<D>>::leaf(&leaf.hash))
+        } else {
+            self.nodes.get(&(h, prefix.clone())).cloned()
+        };
+        hash.unwrap_or_else(|| self.defaults[h].clone())
+    }
+
+    // Rehash the nodes above the leaf at `path`, dropping any that are now
+    // empty subtrees.
+    fn rehash(&mut self, path: &Output<D>) {
+        let bits = self.bits();
+        let mut hash = self.node(0, path);
+        for h in 0..bits {
+            let sibling = self.node(h, &sibling::<D>(path, h));
+            hash = if bit::<D>(path, bits - 1 - h) {
+                node::<D, Rfc6962>(&sibling, &hash)
+            } else {
+                node::<D, Rfc6962>(&hash, &sibling)
+            };
+            let key = (h + 1, prefix::<D>(path, h + 1));
+            if hash == self.defaults[h + 1] {
+                self.nodes.remove(&key);
+            } else {
+                self.nodes.insert(key, hash.clone());
+            }
+        }
+    }
+}
+
+impl<D: Digest, T> Default for SparseMerkleTree<D, T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<D: Digest> SparseProof<D> {
+    /// Check that `leaf` is the hash of the leaf for `key` in a tree with the
+    /// given `root`.
+    pub fn verify(&self, root: &Output<D>, key: &[u8], leaf: &Output<D>) -> bool {
+        self.check(root, key, <Rfc6962 as Scheme<D>>::leaf(leaf))
+    }
+
+    /// Check that there is no leaf for `key` in a tree with the given `root`.
+    pub fn verify_absent(&self, root: &Output<D>, key: &[u8]) -> bool {
+        self.check(root, key, Output::<D>::default())
+    }
+
+    fn check(&self, root: &Output<D>, key: &[u8], mut hash: Output<D>) -> bool {
+        let path = D::digest(key);
+        let bits = D::output_size() * 8;
+        if self.siblings.len() != bits {
+            return false;
+        }
+        let mut default = Output::<D>::default();
+        for (h, sibling) in self.siblings.iter().enumerate() {
+            let sibling = sibling.as_ref().unwrap_or(&default);
+            hash = if bit::<D>(&path, bits - 1 - h) {
+                node::<D, Rfc6962>(sibling, &hash)
+            } else {
+                node::<D, Rfc6962>(&hash, sibling)
+            };
+            default = node::<D, Rfc6962>(&default, &default);
+        }
+        hash == *root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SparseMerkleTree, SparseProof};
+    use crate::testing::leaf;
+    use sha2::Sha256;
+
+    fn key(i: usize) -> Vec<u8> {
+        format!("key {}", i).into_bytes()
+    }
+
+    #[test]
+    fn insert_and_remove() {
+        let mut tree = SparseMerkleTree::<Sha256, usize>::new();
+        let empty = tree.hash();
+        let mut roots = vec![empty];
+        for i in 0..20 {
+            assert!(tree.insert(&key(i), leaf(i)).is_none());
+            assert!(!roots.contains(&tree.hash()));
+            roots.push(tree.hash());
+        }
+        assert_eq!(tree.len(), 20);
+        assert_eq!(tree.get(&key(7)).map(|l| l.data), Some(7));
+        assert!(tree.get(&key(20)).is_none());
+
+        // The root depends only on the contents, not on the order of updates.
+        let mut other = SparseMerkleTree::<Sha256, usize>::new();
+        for i in (0..20).rev() {
+            other.insert(&key(i), leaf(100 + i));
+            other.insert(&key(i), leaf(i));
+        }
+        other.insert(&key(50), leaf(50));
+        assert_eq!(other.remove(&key(50)).map(|l| l.data), Some(50));
+        assert!(other.remove(&key(50)).is_none());
+        assert_eq!(other.hash(), tree.hash());
+
+        for i in (0..20).rev() {
+            assert_eq!(tree.hash(), roots[i + 1]);
+            assert_eq!(tree.remove(&key(i)).map(|l| l.data), Some(i));
+        }
+        assert_eq!(tree.hash(), empty);
+        assert!(tree.nodes.is_empty());
+    }
+
+    #[test]
+    fn proofs() {
+        let mut tree = SparseMerkleTree::<Sha256, usize>::new();
+        let proof = tree.proof(&key(0));
+        assert!(proof.verify_absent(&tree.hash(), &key(0)));
+        assert!(!proof.verify(&tree.hash(), &key(0), &leaf(0).hash));
+        for i in 0..10 {
+            tree.insert(&key(i), leaf(i));
+        }
+        let root = tree.hash();
+        for i in 0..10 {
+            let proof = tree.proof(&key(i));
+            assert!(proof.verify(&root, &key(i), &leaf(i).hash));
+            assert!(!proof.verify(&root, &key(i), &leaf(i + 1).hash));
+            assert!(!proof.verify_absent(&root, &key(i)));
+            assert!(!proof.verify(&root, &key(i + 1), &leaf(i).hash));
+        }
+        for i in 10..20 {
+            let proof = tree.proof(&key(i));
+            assert!(proof.verify_absent(&root, &key(i)));
+            assert!(!proof.verify(&root, &key(i), &leaf(i).hash));
+            // Nearly every sibling of a lone path is empty.
+            assert!(proof.siblings.iter().filter(|s| s.is_some()).count() < 10);
+        }
+        let short = SparseProof::<Sha256> {
+            siblings: tree.proof(&key(3)).siblings[1..].to_vec(),
+        };
+        assert!(!short.verify(&root, &key(3), &leaf(3).hash));
+    }
+}
@@ -0,0 +1,314 @@
+use crate::diff::Descent;
+use crate::scheme::Scheme;
+use crate::MerkleTree;
+use digest::{Digest, Output};
+use std::convert::Infallible;
+use std::fmt;
+use std::sync::mpsc::{self, Receiver, RecvError, Sender};
+use std::vec::Vec;
+
+/// Asks a replica for the hashes of some of its nodes, given as level and
+/// position as in `NodeSource`. An empty request asks only for the size.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Request {
+    pub nodes: Vec<(usize, usize)>,
+}
+
+/// A replica's answer to a `Request`: its number of leaves, and the hashes
+/// asked for in the order they were asked for.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Response<D: Digest> {
+    pub size: usize,
+    pub hashes: Vec<Output<D>>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SyncError<E> {
+    /// The transport failed.
+    Transport(E),
+    /// The replica answered something other than what was asked, or changed
+    /// size part way through.
+    BadResponse,
+}
+
+impl<E: fmt::Display> fmt::Display for SyncError<E> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SyncError::Transport(e) => write!(f, "transport failed: {}", e),
+            SyncError::BadResponse => write!(f, "bad response from replica"),
+        }
+    }
+}
+
+impl<E: fmt::Debug + fmt::Display> std::error::Error for SyncError<E> {}
+
+/// Carries requests to a replica and brings back its responses.
+pub trait Transport<D: Digest> {
+    type Error;
+
+    fn exchange(&mut self, request: Request) -> Result<Response<D>, Self::Error>;
+}
+
+/// The requesting side of a sync, independent of how requests travel.
+///
+/// Each request asks for one level of nodes under those that differed in the
+/// level above, so a sync takes at most one round trip per level of the tree.
+/// Once `request` returns `None`, `finish` gives the leaves that differ.
+pub struct SyncClient<'a, D: Digest, T, S: Scheme<D>> {
+    tree: &'a MerkleTree<D, T, S>,
+    // The comparison, once the replica's size is known.
+    descent: Option<Descent>,
+}
+
+impl<'a, D: Digest, T, S: Scheme<D>> SyncClient<'a, D, T, S> {
+    pub fn new(tree: &'a MerkleTree<D, T, S>) -> Self {
+        Self {
+            tree,
+            descent: None,
+        }
+    }
+
+    /// The next request to send, or `None` if the sync is done.
+    pub fn request(&self) -> Option<Request> {
+        match &self.descent {
+            None => Some(Request { nodes: Vec::new() }),
+            Some(descent) if descent.pending.is_empty() => None,
+            Some(descent) => Some(Request {
+                nodes: descent.pending.clone(),
+            }),
+        }
+    }
+
+    pub fn handle(&mut self, response: Response<D>) -> Result<(), SyncError<Infallible>> {
+        let descent = match &mut self.descent {
+            None if response.hashes.is_empty() => {
+                let n = self.tree.leaves.len();
+                let descent = Descent::new::<D, S>(n, response.size);
+                self.descent = Some(descent.ok_or(SyncError::BadResponse)?);
+                return Ok(());
+            }
+            Some(descent)
+                if descent.m == response.size && descent.pending.len() == response.hashes.len() =>
+            {
+                descent
+            }
+            _ => return Err(SyncError::BadResponse),
+        };
+        descent.step(self.tree, &response.hashes);
+        Ok(())
+    }
+
+    /// The indices of the leaves that differ from the replica's, in ascending
+    /// order, including those that only one side has.
+    pub fn finish(self) -> Vec<usize> {
+        debug_assert!(self.request().is_none(), "sync is not done");
+        match self.descent {
+            Some(descent) => descent.finish(),
+            None => (0..self.tree.leaves.len()).collect(),
+        }
+    }
+}
+
+impl<D: Digest, T, S: Scheme<D>> MerkleTree<D, T, S> {
+    /// Answer a replica's request, or return `None` if it asks for nodes that
+    /// this tree does not have.
+    pub fn respond(&self, request: &Request) -> Option<Response<D>> {
+        let n = self.leaves.len();
+        let mut hashes = Vec::with_capacity(request.nodes.len());
+        for &(h, p) in &request.nodes {
+            hashes.push(self.node_hash(h, p)?.clone());
+        }
+        Some(Response { size: n, hashes })
+    }
+
+    /// Find the leaves that differ from a replica's by driving a `SyncClient`
+    /// over `transport`.
+    pub fn sync<X: Transport<D>>(
+        &self,
+        transport: &mut X,
+    ) -> Result<Vec<usize>, SyncError<X::Error>> {
+        let mut client = SyncClient::new(self);
+        while let Some(request) = client.request() {
+            let response = transport.exchange(request).map_err(SyncError::Transport)?;
+            client
+                .handle(response)
+                .map_err(|_| SyncError::BadResponse)?;
+        }
+        Ok(client.finish())
+    }
+}
+
+/// An in-memory `Transport` to a `ChannelServer`, typically on another
+/// thread. Made with `channel`.
+pub struct Channel<D: Digest> {
+    requests: Sender<Request>,
+    responses: Receiver<Response<D>>,
+}
+
+/// The serving end of a `Channel`.
+pub struct ChannelServer<D: Digest> {
+    requests: Receiver<Request>,
+    responses: Sender<Response<D>>,
+}
+
+pub fn channel<D: Digest>() -> (Channel<D>, ChannelServer<D>) {
+    let (request_tx, request_rx) = mpsc::channel();
+    let (response_tx, response_rx) = mpsc::channel();
+    let client = Channel {
+        requests: request_tx,
+        responses: response_rx,
+    };
+    let server = ChannelServer {
+        requests: request_rx,
+        responses: response_tx,
+    };
+    (client, server)
+}
+
+impl<D: Digest> Transport<D> for Channel<D> {
+    type Error = RecvError;
+
+    fn exchange(&mut self, request: Request) -> Result<Response<D>, RecvError> {
+        self.requests.send(request).map_err(|_| RecvError)?;
+        self.responses.recv()
+    }
+}
+
+impl<D: Digest> ChannelServer<D> {
+    /// Answer requests from `tree` until the `Channel` is dropped or sends a
+    /// bad request.
+    pub fn serve<T, S: Scheme<D>>(self, tree: &MerkleTree<D, T, S>) {
+        for request in self.requests {
+            let response = match tree.respond(&request) {
+                Some(response) => response,
+                None => return,
+            };
+            if self.responses.send(response).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{channel, Request, Response, SyncClient, SyncError, Transport};
+    use crate::testing::{leaf, tree};
+    use crate::{MerkleTree, Rfc6962};
+    use sha2::Sha256;
+    use std::thread;
+
+    #[test]
+    fn sync_over_channel() {
+        for (n, m, changed) in [
+            (0, 0, &[][..]),
+            (5, 0, &[]),
+            (64, 64, &[1, 40]),
+            (50, 77, &[0, 49]),
+        ] {
+            let local = tree::<Rfc6962>(n);
+            let mut remote = tree::<Rfc6962>(m);
+            for &i in changed {
+                remote.replace(i, leaf(1000 + i));
+            }
+            let (mut client, server) = channel();
+            let diff = thread::scope(|s| {
+                s.spawn(|| server.serve(&remote));
+                let diff = local.sync(&mut client);
+                // Hang up, so that the server returns.
+                drop(client);
+                diff
+            });
+            assert_eq!(diff, Ok(local.diff(&remote)), "n={} m={}", n, m);
+        }
+    }
+
+    // Counts round trips to a tree in the same thread.
+    struct Direct<'a> {
+        tree: &'a MerkleTree<Sha256, usize, Rfc6962>,
+        trips: usize,
+    }
+
+    impl Transport<Sha256> for Direct<'_> {
+        type Error = ();
+
+        fn exchange(&mut self, request: Request) -> Result<Response<Sha256>, ()> {
+            self.trips += 1;
+            self.tree.respond(&request).ok_or(())
+        }
+    }
+
+    #[test]
+    fn one_round_trip_per_level() {
+        let local = tree::<Rfc6962>(1000);
+        let mut remote = tree::<Rfc6962>(1000);
+        for i in (0..1000).step_by(97) {
+            remote.replace(i, leaf(5000 + i));
+        }
+        let mut transport = Direct {
+            tree: &remote,
+            trips: 0,
+        };
+        let diff = local.sync(&mut transport).unwrap();
+        assert_eq!(diff, (0..1000).step_by(97).collect::<Vec<_>>());
+        // The size, then each of 11 levels.
+        assert_eq!(transport.trips, 1 + 11);
+    }
+
+    #[test]
+    fn rejects_bad_responses() {
+        let local = tree::<Rfc6962>(8);
+        let mut client = SyncClient::new(&local);
+        client
+            .handle(Response {
+                size: 8,
+                hashes: vec![],
+            })
+            .unwrap();
+        let short = Response {
+            size: 8,
+            hashes: vec![],
+        };
+        assert_eq!(client.handle(short), Err(SyncError::BadResponse));
+        let resized = Response {
+            size: 9,
+            hashes: vec![local.hash()],
+        };
+        assert_eq!(client.handle(resized), Err(SyncError::BadResponse));
+
+        // A size too large for any tree used to overflow computing its height.
+        let mut client = SyncClient::new(&local);
+        let huge = Response {
+            size: usize::MAX,
+            hashes: vec![],
+        };
+        assert_eq!(client.handle(huge), Err(SyncError::BadResponse));
+        assert!(tree::<Rfc6962>(3)
+            .respond(&Request {
+                nodes: vec![(0, 3)]
+            })
+            .is_none());
+    }
+
+    #[test]
+    fn rejects_requests_for_missing_nodes() {
+        let tree = tree::<Rfc6962>(8);
+        for node in [
+            (1, 1 << 63),
+            (0, usize::MAX),
+            (usize::MAX, 0),
+            (3, 1),
+            (4, 0),
+        ] {
+            let request = Request { nodes: vec![node] };
+            assert!(tree.respond(&request).is_none(), "{:?}", node);
+        }
+        let request = Request {
+            nodes: vec![(3, 0), (1, 3)],
+        };
+        assert_eq!(
+            tree.respond(&request).unwrap().hashes,
+            vec![tree.hash(), *tree.node_hash(1, 3).unwrap()]
+        );
+    }
+}
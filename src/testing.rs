@@ -0,0 +1,14 @@
+// Fixtures shared by the tests of every module.
+
+use crate::{Digest, Leaf, MerkleTree, Scheme};
+use sha2::Sha256;
+
+// Leaf `i`: the hash of `i`'s bytes, with `i` as its data.
+pub(crate) fn leaf(i: usize) -> Leaf<Sha256, usize> {
+    Leaf::new(Sha256::digest(&i.to_le_bytes()), i)
+}
+
+// A binary tree over leaves `0..n`.
+pub(crate) fn tree<S: Scheme<Sha256>>(n: usize) -> MerkleTree<Sha256, usize, S> {
+    (0..n).map(leaf).collect()
+}
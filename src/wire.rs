@@ -0,0 +1,318 @@
+use crate::log::ConsistencyProof;
+use crate::proof::{MultiProof, Proof};
+use crate::scheme::{Rfc6962, Scheme};
+use crate::{Leaf, MerkleTree};
+use digest::{Digest, Output};
+use std::convert::TryFrom;
+use std::fmt;
+use std::vec::Vec;
+
+// The binary encoding of trees and proofs. See the README for the layout.
+
+const MAGIC: &[u8; 4] = b"MRKL";
+const VERSION: u8 = 1;
+
+const TREE: u8 = 1;
+const PROOF: u8 = 2;
+const MULTIPROOF: u8 = 3;
+const CONSISTENCY: u8 = 4;
+
+/// Why an encoded tree or proof could not be decoded.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input does not start with the format's magic bytes.
+    Magic,
+    /// The input uses a version of the format that this crate does not know.
+    Version(u8),
+    /// The input holds a different kind of value than was asked for.
+    Kind { expected: u8, found: u8 },
+    /// The input was hashed with a different scheme than was asked for.
+    Scheme { expected: u8, found: u8 },
+    /// The input uses hashes of a different size than the digest.
+    HashSize { expected: usize, found: usize },
+    /// The input ends before the value does.
+    Truncated,
+    /// The input continues after the value ends.
+    TrailingBytes,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Magic => write!(f, "bad magic bytes"),
+            DecodeError::Version(v) => write!(f, "unsupported version {}", v),
+            DecodeError::Kind { expected, found } => {
+                write!(f, "expected kind {}, found {}", expected, found)
+            }
+            DecodeError::Scheme { expected, found } => {
+                write!(f, "expected scheme {}, found {}", expected, found)
+            }
+            DecodeError::HashSize { expected, found } => {
+                write!(f, "expected {}-byte hashes, found {}", expected, found)
+            }
+            DecodeError::Truncated => write!(f, "input is truncated"),
+            DecodeError::TrailingBytes => write!(f, "input has trailing bytes"),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+fn header<D: Digest>(kind: u8, scheme: u8) -> Vec<u8> {
+    let mut out = MAGIC.to_vec();
+    out.extend_from_slice(&[VERSION, kind, scheme, D::output_size() as u8]);
+    out
+}
+
+fn put_hashes<D: Digest>(out: &mut Vec<u8>, hashes: &[Output<D>]) {
+    out.extend_from_slice(&(hashes.len() as u64).to_be_bytes());
+    for hash in hashes {
+        out.extend_from_slice(hash);
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new<D: Digest>(bytes: &'a [u8], kind: u8, scheme: u8) -> Result<Self, DecodeError> {
+        let mut r = Reader { bytes };
+        if r.take(4)? != MAGIC {
+            return Err(DecodeError::Magic);
+        }
+        let found = r.take(4)?;
+        if found[0] != VERSION {
+            return Err(DecodeError::Version(found[0]));
+        }
+        if found[1] != kind {
+            return Err(DecodeError::Kind {
+                expected: kind,
+                found: found[1],
+            });
+        }
+        if found[2] != scheme {
+            return Err(DecodeError::Scheme {
+                expected: scheme,
+                found: found[2],
+            });
+        }
+        if usize::from(found[3]) != D::output_size() {
+            return Err(DecodeError::HashSize {
+                expected: D::output_size(),
+                found: found[3].into(),
+            });
+        }
+        Ok(r)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8], DecodeError> {
+        if self.bytes.len() < n {
+            return Err(DecodeError::Truncated);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+
+    fn u32(&mut self) -> Result<usize, DecodeError> {
+        let mut b = [0; 4];
+        b.copy_from_slice(self.take(4)?);
+        usize::try_from(u32::from_be_bytes(b)).map_err(|_| DecodeError::Truncated)
+    }
+
+    // A count of items that each take at least `size` bytes. Counts that the
+    // rest of the input could not possibly hold are rejected up front, so
+    // that a short input cannot make us allocate a lot.
+    fn count(&mut self, size: usize) -> Result<usize, DecodeError> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        match usize::try_from(u64::from_be_bytes(b)) {
+            Ok(n) if n <= self.bytes.len() / size => Ok(n),
+            _ => Err(DecodeError::Truncated),
+        }
+    }
+
+    fn hash<D: Digest>(&mut self) -> Result<Output<D>, DecodeError> {
+        let mut hash = Output::<D>::default();
+        hash.copy_from_slice(self.take(D::output_size())?);
+        Ok(hash)
+    }
+
+    fn hashes<D: Digest>(&mut self) -> Result<Vec<Output<D>>, DecodeError> {
+        let n = self.count(D::output_size())?;
+        (0..n).map(|_| self.hash::<D>()).collect()
+    }
+
+    fn finish(self) -> Result<(), DecodeError> {
+        if self.bytes.is_empty() {
+            Ok(())
+        } else {
+            Err(DecodeError::TrailingBytes)
+        }
+    }
+}
+
+impl<D: Digest, T: AsRef<[u8]>, S: Scheme<D>> MerkleTree<D, T, S> {
+    /// Encode the tree. Panics if the data of any leaf is 4 GiB or longer,
+    /// which its length field cannot hold.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = header::<D>(TREE, S::ID);
+        out.extend_from_slice(&(self.leaves.len() as u64).to_be_bytes());
+        for leaf in &self.leaves {
+            let data = leaf.data.as_ref();
+            out.extend_from_slice(&leaf.hash);
+            let len = u32::try_from(data.len()).expect("leaf data is too long to encode");
+            out.extend_from_slice(&len.to_be_bytes());
+            out.extend_from_slice(data);
+        }
+        out
+    }
+}
+
+impl<D: Digest, T: From<Vec<u8>>, S: Scheme<D>> MerkleTree<D, T, S> {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new::<D>(bytes, TREE, S::ID)?;
+        let n = r.count(D::output_size() + 4)?;
+        let mut leaves = Vec::with_capacity(n);
+        for _ in 0..n {
+            let hash = r.hash::<D>()?;
+            let len = r.u32()?;
+            leaves.push(Leaf::new(hash, T::from(r.take(len)?.to_vec())));
+        }
+        r.finish()?;
+        Ok(Self::from_leaves(leaves))
+    }
+}
+
+impl<D: Digest, S: Scheme<D>> Proof<D, S> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = header::<D>(PROOF, S::ID);
+        put_hashes::<D>(&mut out, &self.siblings);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new::<D>(bytes, PROOF, S::ID)?;
+        let siblings = r.hashes::<D>()?;
+        r.finish()?;
+        Ok(Self::new(siblings))
+    }
+}
+
+impl<D: Digest, S: Scheme<D>> MultiProof<D, S> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = header::<D>(MULTIPROOF, S::ID);
+        put_hashes::<D>(&mut out, &self.hashes);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new::<D>(bytes, MULTIPROOF, S::ID)?;
+        let hashes = r.hashes::<D>()?;
+        r.finish()?;
+        Ok(Self::new(hashes))
+    }
+}
+
+impl<D: Digest> ConsistencyProof<D> {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = header::<D>(CONSISTENCY, <Rfc6962 as Scheme<D>>::ID);
+        put_hashes::<D>(&mut out, &self.hashes);
+        out
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let mut r = Reader::new::<D>(bytes, CONSISTENCY, <Rfc6962 as Scheme<D>>::ID)?;
+        let hashes = r.hashes::<D>()?;
+        r.finish()?;
+        Ok(Self { hashes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DecodeError;
+    use crate::{Digest, Leaf, Legacy, Log, MerkleTree, Proof, Rfc6962};
+    use sha2::{Sha256, Sha512};
+
+    fn tree(n: usize) -> MerkleTree<Sha256, Vec<u8>, Rfc6962> {
+        let mut tree = MerkleTree::new();
+        for i in 0..n {
+            let data = vec![i as u8; i];
+            tree.push(Leaf::new(Sha256::digest(&data), data));
+        }
+        tree
+    }
+
+    #[test]
+    fn tree_round_trip() {
+        for n in 0..=9 {
+            let tree = tree(n);
+            let bytes = tree.to_bytes();
+            let loaded = MerkleTree::<Sha256, Vec<u8>, Rfc6962>::from_bytes(&bytes).unwrap();
+            assert_eq!(loaded.hash(), tree.hash());
+            assert_eq!(loaded.to_bytes(), bytes);
+        }
+    }
+
+    #[test]
+    fn layout() {
+        let bytes = tree(2).to_bytes();
+        assert_eq!(&bytes[..8], b"MRKL\x01\x01\x01\x20");
+        assert_eq!(&bytes[8..16], &2u64.to_be_bytes());
+        assert_eq!(&bytes[16..48], &Sha256::digest(b"")[..]);
+        assert_eq!(&bytes[48..52], &0u32.to_be_bytes());
+        assert_eq!(&bytes[84..89], b"\x00\x00\x00\x01\x01");
+        assert_eq!(bytes.len(), 89);
+    }
+
+    #[test]
+    fn rejects_bad_input() {
+        let bytes = tree(5).to_bytes();
+        let decode = |b: &[u8]| MerkleTree::<Sha256, Vec<u8>, Rfc6962>::from_bytes(b).err();
+        for i in 0..bytes.len() {
+            assert_eq!(decode(&bytes[..i]), Some(DecodeError::Truncated), "i={}", i);
+        }
+        let mut long = bytes.clone();
+        long.push(0);
+        assert_eq!(decode(&long), Some(DecodeError::TrailingBytes));
+        assert_eq!(
+            MerkleTree::<Sha256, Vec<u8>, Legacy>::from_bytes(&bytes).err(),
+            Some(DecodeError::Scheme {
+                expected: 0,
+                found: 1
+            })
+        );
+        assert_eq!(
+            MerkleTree::<Sha512, Vec<u8>, Rfc6962>::from_bytes(&bytes).err(),
+            Some(DecodeError::HashSize {
+                expected: 64,
+                found: 32
+            })
+        );
+        let proof = tree(5).proof(3).to_bytes();
+        assert_eq!(
+            decode(&proof),
+            Some(DecodeError::Kind {
+                expected: 1,
+                found: 2
+            })
+        );
+    }
+
+    #[test]
+    fn proof_round_trip() {
+        let tree = tree(7);
+        let proof = Proof::<Sha256, Rfc6962>::from_bytes(&tree.proof(5).to_bytes()).unwrap();
+        assert!(proof.verify(&tree.hash(), &Sha256::digest(&[5; 5]), 5, 7));
+
+        let mut log = Log::<Sha256, ()>::new();
+        for i in 0..7u8 {
+            log.push(Leaf::new(Sha256::digest(&[i]), ()));
+        }
+        let bytes = log.consistency(3, 7).to_bytes();
+        let proof = crate::ConsistencyProof::<Sha256>::from_bytes(&bytes).unwrap();
+        assert!(proof.verify(3, 7, &log.root(3), &log.root(7)));
+    }
+}